@@ -0,0 +1,163 @@
+use std::{collections::BTreeMap, env, fmt::Write, fs, path::Path};
+
+#[path = "src/renderer/shader_source.rs"]
+mod shader_source;
+
+/*
+* Generates the bind group layouts that are shared between rust and wgsl from the shader sources,
+* so the layouts always match the shaders. The bind groups are still written by hand against the
+* generated binding constants: renaming or removing a binding breaks the build where rust uses it,
+* but a new binding without a BindGroupEntry only fails bind group validation at runtime.
+*
+* Each layout is the union of a bind group across the listed shaders. Bindings have to agree on
+* name and type in every shader that declares them. Every binding is visible to the vertex and fragment
+* stages (plus compute if a compute shader uses it), not just to the stages that use it now: the layouts
+* are frozen at build time, so hot reloading a shader that starts reading a binding in another stage
+* has to keep working. Adding, removing or retyping a binding still needs a rebuild.
+* The output is included by src/renderer/bind_group_layouts.rs.
+*/
+const SHADER_DIR: &str = "src/renderer/shaders";
+const LAYOUTS: &[(&str, &[(&str, u32)])] = &[
+    ("camera", &[("pbr", 0), ("skybox", 0), ("post_processing", 1)]),
+    ("lights", &[("pbr", 1)]),
+    ("material", &[("pbr", 2)]),
+    ("shadow_caster", &[("shadow", 0)]),
+];
+
+struct Binding {
+    name: String,
+    ty: String,
+    stages: u32,
+    declared_in: String,
+}
+
+// wgpu::ShaderStages bits
+const VERTEX: u32 = 1;
+const FRAGMENT: u32 = 2;
+const COMPUTE: u32 = 4;
+
+fn binding_type(module: &naga::Module, global: &naga::GlobalVariable) -> Result<String, String> {
+    let buffer = |ty: &str| format!(
+        "wgpu::BindingType::Buffer {{ ty: wgpu::BufferBindingType::{}, has_dynamic_offset: false, min_binding_size: None }}", ty
+    );
+    match global.space {
+        naga::AddressSpace::Uniform => return Ok(buffer("Uniform")),
+        naga::AddressSpace::Storage { access } => {
+            let read_only = !access.contains(naga::StorageAccess::STORE);
+            return Ok(buffer(&format!("Storage {{ read_only: {} }}", read_only)));
+        },
+        naga::AddressSpace::Handle => {},
+        other => return Err(format!("unsupported address space {:?}", other)),
+    }
+    match &module.types[global.ty].inner {
+        naga::TypeInner::Sampler { comparison } => Ok(format!(
+            "wgpu::BindingType::Sampler(wgpu::SamplerBindingType::{})",
+            if *comparison { "Comparison" } else { "Filtering" }
+        )),
+        naga::TypeInner::Image { dim, arrayed, class } => {
+            let view_dimension = match (dim, arrayed) {
+                (naga::ImageDimension::D1, false) => "D1",
+                (naga::ImageDimension::D2, false) => "D2",
+                (naga::ImageDimension::D2, true) => "D2Array",
+                (naga::ImageDimension::D3, false) => "D3",
+                (naga::ImageDimension::Cube, false) => "Cube",
+                (naga::ImageDimension::Cube, true) => "CubeArray",
+                other => return Err(format!("unsupported texture dimension {:?}", other)),
+            };
+            let (sample_type, multisampled) = match class {
+                naga::ImageClass::Sampled { kind: naga::ScalarKind::Float, multi } =>
+                    ("Float { filterable: true }", *multi),
+                naga::ImageClass::Sampled { kind: naga::ScalarKind::Sint, multi } => ("Sint", *multi),
+                naga::ImageClass::Sampled { kind: naga::ScalarKind::Uint, multi } => ("Uint", *multi),
+                naga::ImageClass::Depth { multi } => ("Depth", *multi),
+                other => return Err(format!("unsupported texture class {:?}", other)),
+            };
+            Ok(format!(
+                "wgpu::BindingType::Texture {{ sample_type: wgpu::TextureSampleType::{}, view_dimension: wgpu::TextureViewDimension::{}, multisampled: {} }}",
+                sample_type, view_dimension, multisampled
+            ))
+        },
+        other => Err(format!("unsupported handle type {:?}", other)),
+    }
+}
+
+fn collect_group(shader: &str, group: u32, bindings: &mut BTreeMap<u32, Binding>) -> Result<(), String> {
+    let path = format!("{}/{}.wgsl", SHADER_DIR, shader);
+    let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let source = shader_source::resolve_includes(&path, &source)?;
+    let module = naga::front::wgsl::parse_str(&source).map_err(|e| e.emit_to_string_with_path(&source, &path))?;
+    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| e.emit_to_string(&source))?;
+
+    for (handle, global) in module.global_variables.iter() {
+        let resource = match &global.binding {
+            Some(resource) if resource.group == group => resource,
+            _ => continue,
+        };
+        let name = global.name.clone().unwrap_or_default();
+        let ty = binding_type(&module, global).map_err(|e| format!("{}: {}: {}", path, name, e))?;
+
+        let mut stages = 0;
+        for (ep_index, entry_point) in module.entry_points.iter().enumerate() {
+            if !info.get_entry_point(ep_index)[handle].is_empty() {
+                stages |= match entry_point.stage {
+                    naga::ShaderStage::Vertex => VERTEX,
+                    naga::ShaderStage::Fragment => FRAGMENT,
+                    naga::ShaderStage::Compute => COMPUTE,
+                };
+            }
+        }
+
+        match bindings.get_mut(&resource.binding) {
+            Some(existing) => {
+                if existing.name != name || existing.ty != ty {
+                    return Err(format!(
+                        "{}: @group({}) @binding({}) is `{}` here but `{}` in {}, the declarations have to match",
+                        path, group, resource.binding, name, existing.name, existing.declared_in
+                    ));
+                }
+                existing.stages |= stages;
+            },
+            None => {
+                bindings.insert(resource.binding, Binding { name, ty, stages, declared_in: path.clone() });
+            },
+        }
+    }
+    Ok(())
+}
+
+fn generate() -> Result<String, String> {
+    let mut out = String::from("// generated by build.rs from the wgsl sources, edit the shaders instead\n");
+    for (layout, groups) in LAYOUTS {
+        let mut bindings = BTreeMap::new();
+        for (shader, group) in *groups {
+            collect_group(shader, *group, &mut bindings)?;
+        }
+
+        writeln!(out, "\npub mod {} {{", layout).unwrap();
+        for (index, binding) in &bindings {
+            writeln!(out, "    pub const {}: u32 = {};", binding.name.to_uppercase(), index).unwrap();
+        }
+        writeln!(out, "\n    pub const ENTRIES: &[wgpu::BindGroupLayoutEntry] = &[").unwrap();
+        for (index, binding) in &bindings {
+            let stages = VERTEX | FRAGMENT | binding.stages;
+            writeln!(
+                out,
+                "        wgpu::BindGroupLayoutEntry {{ binding: {}, visibility: wgpu::ShaderStages::from_bits_truncate({}), ty: {}, count: None }},",
+                index, stages, binding.ty
+            ).unwrap();
+        }
+        writeln!(out, "    ];\n}}").unwrap();
+    }
+    Ok(out)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+
+    let generated = generate().unwrap_or_else(|e| panic!("\n{}\n", e));
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("bind_group_layouts.rs");
+    fs::write(out_path, generated).unwrap();
+}
@@ -0,0 +1,158 @@
+use std::time::Instant;
+
+use cgmath::{Deg, InnerSpace, Point3, Quaternion, Rotation, Rotation3, Vector3};
+use winit::keyboard::KeyCode;
+
+use crate::renderer::camera::Camera;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraMode {
+    // rotates around the focus point, the wheel changes the distance
+    Orbit,
+    // WASD moves, Q / E go down / up, looking around needs the mouse held down
+    Fly,
+}
+
+/*
+* Turns input into a camera pose. The controller owns the pose and writes eye and target,
+* the camera's own rotation (rot_x / rot_y) is left at zero.
+*/
+pub struct CameraController {
+    pub mode: CameraMode,
+    // world units per second in fly mode
+    pub move_speed: f32,
+    // mouse motion (in pixels) per degree of rotation
+    pub look_sensitivity: f32,
+    // speed multiplier while shift is held
+    pub fast_multiplier: f32,
+    yaw: Deg<f32>,
+    pitch: Deg<f32>,
+    focus: Point3<f32>,
+    distance: f32,
+    position: Point3<f32>,
+    // forward, right, up; -1, 0 or 1 each
+    movement: [i8; 3],
+    held: [bool; 6],
+    fast: bool,
+    last_update: Option<Instant>,
+}
+
+// looking straight up or down would make the view direction parallel to the up vector
+const MAX_PITCH: Deg<f32> = Deg(89.0);
+
+// key order of the held array
+const MOVEMENT_KEYS: [KeyCode; 6] = [KeyCode::KeyW, KeyCode::KeyS, KeyCode::KeyD, KeyCode::KeyA, KeyCode::KeyE, KeyCode::KeyQ];
+
+impl CameraController {
+    // starts orbiting the camera's target from where the camera is
+    pub fn new(camera: &Camera) -> Self {
+        let offset = camera.eye - camera.target;
+        let distance = offset.magnitude();
+        let (yaw, pitch) = if distance > 0.0 {
+            let dir = offset / distance;
+            (Deg::from(cgmath::Rad(dir.x.atan2(dir.z))), Deg::from(cgmath::Rad((-dir.y).asin())))
+        } else {
+            (Deg(0.0), Deg(0.0))
+        };
+        Self {
+            mode: CameraMode::Orbit,
+            move_speed: 2.0,
+            look_sensitivity: 5.0,
+            fast_multiplier: 10.0,
+            yaw, pitch,
+            focus: camera.target,
+            distance,
+            position: camera.eye,
+            movement: [0; 3],
+            held: [false; 6],
+            fast: false,
+            last_update: None,
+        }
+    }
+
+    fn rotation(&self) -> Quaternion<f32> {
+        Quaternion::from_angle_y(self.yaw) * Quaternion::from_angle_x(self.pitch)
+    }
+
+    fn forward(&self) -> Vector3<f32> {
+        self.rotation().rotate_vector(-Vector3::unit_z())
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            CameraMode::Orbit => {
+                self.position = self.focus - self.forward() * self.distance;
+                CameraMode::Fly
+            },
+            CameraMode::Fly => {
+                // orbit whatever is in front of the camera at the last orbit distance
+                self.focus = self.position + self.forward() * self.distance;
+                CameraMode::Orbit
+            },
+        };
+    }
+
+    // mouse motion in pixels
+    pub fn rotate(&mut self, dx: f32, dy: f32) {
+        self.yaw -= Deg(dx / self.look_sensitivity);
+        self.pitch = Deg((self.pitch - Deg(dy / self.look_sensitivity)).0.clamp(-MAX_PITCH.0, MAX_PITCH.0));
+    }
+
+    // positive lines zoom in, in fly mode the camera moves forward instead
+    pub fn zoom(&mut self, lines: f32) {
+        let step = lines * if self.fast { self.fast_multiplier } else { 1.0 };
+        match self.mode {
+            CameraMode::Orbit => self.distance = (self.distance - step).max(0.0),
+            CameraMode::Fly => self.position += self.forward() * step,
+        }
+    }
+
+    // returns whether the key is one of the controller's
+    pub fn set_key(&mut self, code: KeyCode, pressed: bool) -> bool {
+        if code == KeyCode::ShiftLeft {
+            self.fast = pressed;
+            return true;
+        }
+        let Some(idx) = MOVEMENT_KEYS.iter().position(|k| *k == code) else {
+            return false;
+        };
+        self.held[idx] = pressed;
+        for axis in 0..3 {
+            self.movement[axis] = self.held[axis * 2] as i8 - self.held[axis * 2 + 1] as i8;
+        }
+        true
+    }
+
+    // whether the camera keeps moving without further input, so frames have to keep coming
+    pub fn is_moving(&self) -> bool {
+        self.mode == CameraMode::Fly && self.movement != [0; 3]
+    }
+
+    // advances the movement by the time since the last call and writes the pose to the camera
+    pub fn apply(&mut self, camera: &mut Camera) {
+        let now = Instant::now();
+        // a long gap means movement just started, don't jump by the time spent idle
+        let dt = self.last_update.map_or(0.0, |last| (now - last).as_secs_f32().min(0.1));
+        self.last_update = if self.is_moving() { Some(now) } else { None };
+
+        if self.is_moving() {
+            let forward = self.forward();
+            let right = forward.cross(Vector3::unit_y()).normalize();
+            let [f, r, u] = self.movement.map(|m| m as f32);
+            let direction = forward * f + right * r + Vector3::unit_y() * u;
+            if direction.magnitude2() > 0.0 {
+                let speed = self.move_speed * if self.fast { self.fast_multiplier } else { 1.0 };
+                self.position += direction.normalize() * speed * dt;
+            }
+        }
+
+        let (eye, target) = match self.mode {
+            CameraMode::Orbit => (self.focus - self.forward() * self.distance, self.focus),
+            CameraMode::Fly => (self.position, self.position + self.forward()),
+        };
+        camera.eye = eye;
+        camera.target = target;
+        camera.rot_x = Deg(0.0);
+        camera.rot_y = Deg(0.0);
+    }
+}
@@ -1,19 +1,59 @@
 use std::{sync::{Arc, Mutex, mpsc::channel}, path::Path, time::Duration, thread};
 use cgmath::{InnerSpace, Rotation3};
-use winit::{application::ApplicationHandler, dpi::PhysicalPosition, event::{DeviceEvent, ElementState, Event, KeyEvent, MouseScrollDelta, WindowEvent}, event_loop::{ActiveEventLoop, ControlFlow, EventLoop}, keyboard::{KeyCode, PhysicalKey}, window::{Window, WindowId}};
-use notify::{Watcher, RecommendedWatcher, Config};
+use winit::{application::ApplicationHandler, dpi::PhysicalPosition, event::{DeviceEvent, ElementState, Event, KeyEvent, MouseScrollDelta, WindowEvent}, event_loop::{ActiveEventLoop, ControlFlow, EventLoop}, keyboard::{KeyCode, PhysicalKey}, window::{CursorGrabMode, Window, WindowId}};
+use notify::{Watcher, RecommendedWatcher, PollWatcher, Config};
 use pollster::FutureExt as _;
 
+pub mod camera_controller;
+pub mod logger;
 pub mod renderer;
 
-use renderer::{gltf::GLTF, renderer::Renderer};
+use camera_controller::{CameraController, CameraMode};
+use renderer::{camera::ClipPlanes, gltf::GLTF, renderer::Renderer};
+
+// pixel scroll deltas per wheel notch, roughly what browsers use
+const PIXELS_PER_SCROLL_LINE: f32 = 20f32;
+
+const SHADER_DIR: &str = "src/renderer/shaders/";
+const SHADER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/*
+* Native file events by default. Some network filesystems never deliver events, set
+* WGPU_TEST_WATCH=poll to poll instead, changes are then detected by comparing file contents.
+* Polling is also the fallback when the native watcher can't be created.
+*/
+fn watch_shaders(tx: std::sync::mpsc::Sender<notify::Result<notify::Event>>) -> notify::Result<Box<dyn Watcher + Send>> {
+    let force_poll = std::env::var("WGPU_TEST_WATCH").is_ok_and(|v| v == "poll");
+    if !force_poll {
+        let native = RecommendedWatcher::new(tx.clone(), Config::default())
+            .and_then(|mut watcher| watcher.watch(Path::new(SHADER_DIR), notify::RecursiveMode::Recursive).map(|_| watcher));
+        match native {
+            Ok(watcher) => return Ok(Box::new(watcher)),
+            Err(e) => log::warn!("native file watcher unavailable ({}), polling {} instead", e, SHADER_DIR),
+        }
+    }
+    let config = Config::default()
+        .with_poll_interval(SHADER_POLL_INTERVAL)
+        .with_compare_contents(true);
+    let mut watcher = PollWatcher::new(tx, config)?;
+    watcher.watch(Path::new(SHADER_DIR), notify::RecursiveMode::Recursive)?;
+    Ok(Box::new(watcher))
+}
+
+enum AppEvent {
+    // sent by the watcher thread
+    ShadersChanged,
+}
 
 struct App<'surface> {
     renderer: Option<Arc<Mutex<Renderer<'surface>>>>,
     window: Option<Arc<Window>>,
     scene: Arc<GLTF>,
+    camera_controller: Option<CameraController>,
     mouse_btn_is_pressed: bool,
-    shift_is_pressed: bool,
+    right_mouse_btn_is_pressed: bool,
+    // H hides one primitive of one instance, see toggle_test_primitive_mask
+    test_primitive_hidden: bool,
 }
 
 impl App<'_> {
@@ -22,28 +62,104 @@ impl App<'_> {
     ) -> Self {
         Self {
             renderer: None, window: None,
-            scene: Arc::new(gltf), mouse_btn_is_pressed: false, shift_is_pressed: false,
+            scene: Arc::new(gltf), camera_controller: None,
+            mouse_btn_is_pressed: false, right_mouse_btn_is_pressed: false,
+            test_primitive_hidden: false,
         }
     }
 
+    // writes the controller's pose to the camera
+    fn apply_camera_controller(&mut self) {
+        if let (Some(renderer_arc_mutex), Some(controller)) = (&self.renderer, &mut self.camera_controller) {
+            let mut renderer = renderer_arc_mutex.lock().unwrap();
+            controller.apply(renderer.get_camera_mut());
+            renderer.update_camera();
+            self.window.as_mut().unwrap().request_redraw();
+        }
+    }
+
+    // positive lines zoom in
+    fn zoom(&mut self, lines: f32) {
+        if let Some(controller) = &mut self.camera_controller {
+            controller.zoom(lines);
+        }
+        self.apply_camera_controller();
+    }
+
+    // mouse look in fly mode hides and locks the cursor while the right button is held
+    fn set_cursor_grab(&self, grab: bool) {
+        let window = match &self.window {
+            Some(window) => window,
+            None => return,
+        };
+        let result = if grab {
+            window.set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+        } else {
+            window.set_cursor_grab(CursorGrabMode::None)
+        };
+        if let Err(e) = result {
+            log::warn!("cursor grab: {}", e);
+        }
+        window.set_cursor_visible(!grab);
+    }
+
+    /*
+    * Hides the first primitive of the first instance of the first mesh with several primitives, the
+    * other instances keep drawing it, so the instances are split into separately drawn mask groups.
+    */
+    fn toggle_test_primitive_mask(&mut self) {
+        let renderer_arc_mutex = match &self.renderer {
+            Some(renderer_arc_mutex) => renderer_arc_mutex,
+            None => return,
+        };
+        self.test_primitive_hidden = !self.test_primitive_hidden;
+        let mesh_idx = self.scene.scene.meshes.iter().position(|m| m.primitives.len() > 1).unwrap_or(0);
+        let mask = if self.test_primitive_hidden { !1 } else { u64::MAX };
+        match renderer_arc_mutex.lock().unwrap().set_primitive_mask(mesh_idx, 0, mask) {
+            Ok(()) => log::info!("mesh {} instance 0 primitive 0 {}", mesh_idx, if self.test_primitive_hidden { "hidden" } else { "shown" }),
+            Err(e) => log::warn!("primitive mask: {}", e),
+        }
+        self.window.as_ref().unwrap().request_redraw();
+    }
+
     pub fn reload_shaders(&mut self) {
         if let Some(ref mut renderer_arc_mutex) = self.renderer {
             let mut renderer = renderer_arc_mutex.lock().unwrap();
-            match renderer.reload_pbr_pipeline() {
-                Ok(_) => {},
-                Err(e) => eprintln!("render error: {:?}", e),
+            match renderer.reload_pipelines() {
+                Ok(_) => {
+                    log::info!("shaders reloaded");
+                    self.window.as_ref().unwrap().request_redraw();
+                },
+                Err(e) => log::error!("shader reload failed, keeping the old pipelines\n{}", e),
             }
         }
     }
 }
 
-impl<'surface> ApplicationHandler for App<'surface> {
+impl<'surface> ApplicationHandler<AppEvent> for App<'surface> {
+    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: AppEvent) {
+        match event {
+            AppEvent::ShadersChanged => self.reload_shaders(),
+        }
+    }
+
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = Arc::new(event_loop.create_window(Window::default_attributes()).unwrap());
         self.window = Some(window.clone());
 
         let meshes = self.scene.to_pbr_meshes();
-        let temp_renderer = Renderer::new(window.clone(), meshes).block_on();
+        let mut temp_renderer = match Renderer::new(window.clone(), meshes).block_on() {
+            Ok(renderer) => renderer,
+            Err(e) => {
+                log::error!("{}", e);
+                event_loop.exit();
+                return;
+            },
+        };
+        self.camera_controller = Some(CameraController::new(temp_renderer.get_camera_mut()));
+        temp_renderer.get_lights_mut().punctual_lights = self.scene.to_punctual_lights();
+        temp_renderer.update_lights();
         let renderer_arc_mutex = Arc::new(Mutex::new(temp_renderer));
         self.renderer = Some(renderer_arc_mutex.clone());
     }
@@ -54,27 +170,29 @@ impl<'surface> ApplicationHandler for App<'surface> {
                 event_loop.exit();
             },
             WindowEvent::RedrawRequested => {
+                // held movement keys keep the frames coming
+                if self.camera_controller.as_ref().is_some_and(CameraController::is_moving) {
+                    self.apply_camera_controller();
+                }
                 if let Some(ref mut renderer_arc_mutex) = self.renderer {
                     let mut renderer = renderer_arc_mutex.lock().unwrap();
                     match renderer.render() {
                         Ok(_) => {},
-                        Err(e) => eprintln!("render error: {:?}", e),
+                        Err(e) => log::error!("render error: {:?}", e),
                     }
                 }
             },
-            WindowEvent::MouseWheel { device_id, delta, phase } => {
-                if let Some(ref mut renderer_arc_mutex) = self.renderer {
-                    let mut renderer = renderer_arc_mutex.lock().unwrap();
-                    let camera = renderer.get_camera_mut();
-                    match delta {
-                        MouseScrollDelta::LineDelta(x, y) => {
-                            camera.eye.z = (camera.eye.z + ((if self.shift_is_pressed { 10f32 } else { 1f32 }) * -y as f32)).max(0f32);
-                            renderer.update_camera();
-                            self.window.as_mut().unwrap().request_redraw();
-                        },
-                        MouseScrollDelta::PixelDelta(pos) => ()
-                    }
-                }
+            WindowEvent::MouseWheel { delta, .. } => {
+                // touchpads (and some platforms' wheels) report pixels, normalize them to wheel notches
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / PIXELS_PER_SCROLL_LINE,
+                };
+                self.zoom(lines);
+            },
+            WindowEvent::PinchGesture { delta, .. } => {
+                // delta is the relative change in magnification, one line is roughly a 10% pinch
+                self.zoom(delta as f32 * 10f32);
             },
             WindowEvent::MouseInput { device_id, state, button } => {
                 match button {
@@ -88,16 +206,66 @@ impl<'surface> ApplicationHandler for App<'surface> {
                             },
                         }
                     },
+                    winit::event::MouseButton::Right => {
+                        self.right_mouse_btn_is_pressed = state == ElementState::Pressed;
+                        let fly = self.camera_controller.as_ref().is_some_and(|c| c.mode == CameraMode::Fly);
+                        self.set_cursor_grab(fly && self.right_mouse_btn_is_pressed);
+                    },
                     _ => ()
                 };
             },
             WindowEvent::KeyboardInput { device_id, event, is_synthetic } => {
                 match event {
-                    KeyEvent { physical_key: PhysicalKey::Code(KeyCode::ShiftLeft), state: ElementState::Pressed, .. } => {
-                        self.shift_is_pressed = true;
+                    KeyEvent { physical_key: PhysicalKey::Code(KeyCode::KeyC), state: ElementState::Pressed, repeat: false, .. } => {
+                        if let Some(controller) = &mut self.camera_controller {
+                            controller.toggle_mode();
+                            log::info!("camera mode {:?}", controller.mode);
+                        }
+                        // leaving fly mode while looking around
+                        self.set_cursor_grab(false);
+                        self.apply_camera_controller();
+                    },
+                    KeyEvent { physical_key: PhysicalKey::Code(KeyCode::KeyH), state: ElementState::Pressed, repeat: false, .. } => {
+                        self.toggle_test_primitive_mask();
                     },
-                    KeyEvent { physical_key: PhysicalKey::Code(KeyCode::ShiftLeft), state: ElementState::Released, .. } => {
-                        self.shift_is_pressed = false;
+                    KeyEvent { physical_key: PhysicalKey::Code(KeyCode::KeyF), state: ElementState::Pressed, .. } => {
+                        if let Some(ref mut renderer_arc_mutex) = self.renderer {
+                            let mut renderer = renderer_arc_mutex.lock().unwrap();
+                            let camera = renderer.get_camera_mut();
+                            camera.clip_planes = match camera.clip_planes {
+                                ClipPlanes::Manual => ClipPlanes::AutoFit { min_znear: 0.01, margin: 0.05 },
+                                ClipPlanes::AutoFit { .. } => ClipPlanes::Manual,
+                            };
+                            log::info!("clip planes {:?}", camera.clip_planes);
+                            renderer.update_camera();
+                            self.window.as_mut().unwrap().request_redraw();
+                        }
+                    },
+                    KeyEvent { physical_key: PhysicalKey::Code(code @ (KeyCode::KeyT | KeyCode::KeyG | KeyCode::KeyV | KeyCode::BracketLeft | KeyCode::BracketRight)), state: ElementState::Pressed, .. } => {
+                        if let Some(ref mut renderer_arc_mutex) = self.renderer {
+                            let mut renderer = renderer_arc_mutex.lock().unwrap();
+                            let post_settings = &mut renderer.get_camera_mut().post_settings;
+                            match code {
+                                KeyCode::KeyT => post_settings.tonemapper = post_settings.tonemapper.next(),
+                                KeyCode::KeyG => post_settings.lut_weight = if post_settings.lut_weight > 0.0 { 0.0 } else { 1.0 },
+                                KeyCode::KeyV => post_settings.debug_view = post_settings.debug_view.next(),
+                                KeyCode::BracketLeft => post_settings.ev100 -= 0.5,
+                                _ => post_settings.ev100 += 0.5,
+                            }
+                            log::info!(
+                                "tonemapper {:?}, ev100 {}, lut weight {}, debug view {:?}",
+                                post_settings.tonemapper, post_settings.ev100, post_settings.lut_weight, post_settings.debug_view
+                            );
+                            renderer.update_camera();
+                            self.window.as_mut().unwrap().request_redraw();
+                        }
+                    },
+                    KeyEvent { physical_key: PhysicalKey::Code(code), state, .. } => {
+                        let handled = self.camera_controller.as_mut()
+                            .is_some_and(|c| c.set_key(code, state == ElementState::Pressed));
+                        if handled {
+                            self.apply_camera_controller();
+                        }
                     },
                     _ => ()
                 }
@@ -128,16 +296,11 @@ impl<'surface> ApplicationHandler for App<'surface> {
     ) {
         match event {
             DeviceEvent::MouseMotion { delta: (x, y) } => {
-                if !self.mouse_btn_is_pressed { return (); }
-                if let Some(ref mut renderer_arc_mutex) = self.renderer {
-                    let mut renderer = renderer_arc_mutex.lock().unwrap();
-                    let camera = renderer.get_camera_mut();
-                    let sensitivity = 5f32;
-                    camera.rot_x = camera.rot_x - cgmath::Deg(x as f32 / sensitivity);
-                    camera.rot_y = camera.rot_y - cgmath::Deg(y as f32 / sensitivity);
-                    renderer.update_camera();
-                    self.window.as_mut().unwrap().request_redraw();
+                if !self.mouse_btn_is_pressed && !self.right_mouse_btn_is_pressed { return (); }
+                if let Some(controller) = &mut self.camera_controller {
+                    controller.rotate(x as f32, y as f32);
                 }
+                self.apply_camera_controller();
             },
             _ => (),
         }
@@ -146,14 +309,14 @@ impl<'surface> ApplicationHandler for App<'surface> {
 
 pub fn run(gltf: GLTF) {
     let app = Arc::new(Mutex::new(App::new(gltf)));
-    let event_loop = EventLoop::new().unwrap();
+    let event_loop = EventLoop::<AppEvent>::with_user_event().build().unwrap();
     event_loop.set_control_flow(ControlFlow::Wait);
 
     let (tx, rx) = channel();
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()).unwrap();
-    watcher.watch(Path::new("src/renderer/shaders/"), notify::RecursiveMode::Recursive).unwrap();
+    let _watcher = watch_shaders(tx).unwrap();
 
-    let app_clone1 = app.clone();
+    // the watcher thread only notifies the event loop, the reload happens on the main thread
+    let proxy = event_loop.create_proxy();
     thread::spawn(move || {
         loop {
             match rx.recv_timeout(Duration::from_secs(1)) {
@@ -161,7 +324,8 @@ pub fn run(gltf: GLTF) {
                     match event {
                         Ok(e) => {
                             match e.kind {
-                                notify::EventKind::Modify(notify::event::ModifyKind::Any) => {
+                                // the poll watcher reports content changes as Data modifications
+                                notify::EventKind::Modify(notify::event::ModifyKind::Any | notify::event::ModifyKind::Data(_)) => {
                                     let mut should_reload = true;
                                     for path in &e.paths {
                                         if path.to_string_lossy().ends_with('~') {
@@ -169,15 +333,15 @@ pub fn run(gltf: GLTF) {
                                             break;
                                         }
                                     }
-                                    if should_reload {
-                                        let mut app = app_clone1.lock().unwrap();
-                                        app.reload_shaders();
+                                    if should_reload && proxy.send_event(AppEvent::ShadersChanged).is_err() {
+                                        // the event loop is gone
+                                        return;
                                     }
                                 },
                                 _ => {}
                             }
                         },
-                        Err(e) => println!("watch error: {:?}", e),
+                        Err(e) => log::error!("watch error: {:?}", e),
                     }
                 }
                 Err(e) => {},
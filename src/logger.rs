@@ -0,0 +1,102 @@
+use std::{io::Write, sync::{atomic::{AtomicU64, Ordering}, OnceLock}, time::Instant};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/*
+* Minimal stderr logger for the log facade.
+* Levels are configured with the WGPU_TEST_LOG environment variable, a comma separated list of
+* `level` or `module::path=level` entries, the longest matching module path wins, e.g.
+* WGPU_TEST_LOG=info,wgpu_test_3::renderer::gltf=debug,wgpu_core=warn
+*/
+const ENV_VAR: &str = "WGPU_TEST_LOG";
+const DEFAULT_FILTERS: &str = "warn,wgpu_test_3=info";
+
+static FRAME: AtomicU64 = AtomicU64::new(0);
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    start: Instant,
+    default_level: LevelFilter,
+    // sorted longest module path first
+    module_levels: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    fn parse(filters: &str) -> Self {
+        let mut default_level = LevelFilter::Warn;
+        let mut module_levels = vec![];
+        for entry in filters.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((module, level)) => match level.parse() {
+                    Ok(level) => module_levels.push((module.to_string(), level)),
+                    Err(_) => eprintln!("{}: invalid level in '{}'", ENV_VAR, entry),
+                },
+                None => match entry.parse() {
+                    Ok(level) => default_level = level,
+                    Err(_) => eprintln!("{}: invalid level '{}'", ENV_VAR, entry),
+                },
+            }
+        }
+        module_levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Self { start: Instant::now(), default_level, module_levels }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_levels.iter()
+            .find(|(module, _)| {
+                target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels.iter().map(|(_, level)| *level).fold(self.default_level, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let thread = std::thread::current();
+        // a single write per record so lines from different threads don't interleave
+        let line = format!(
+            "[{:>9.3}s frame {}] {:<5} {} {}: {}\n",
+            self.start.elapsed().as_secs_f32(),
+            FRAME.load(Ordering::Relaxed),
+            record.level(),
+            thread.name().unwrap_or("unnamed"),
+            record.target(),
+            record.args(),
+        );
+        let _ = std::io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+pub fn init() {
+    let filters = std::env::var(ENV_VAR).unwrap_or_else(|_| DEFAULT_FILTERS.to_string());
+    let logger = LOGGER.get_or_init(|| Logger::parse(&filters));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.max_level());
+    }
+}
+
+// included in every log line, so messages can be matched to the frame that produced them
+pub fn next_frame() {
+    FRAME.fetch_add(1, Ordering::Relaxed);
+}
+
+// the frame log lines are tagged with, also the shaders' FrameGlobals.frame_index
+pub fn frame() -> u64 {
+    FRAME.load(Ordering::Relaxed)
+}
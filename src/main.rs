@@ -3,14 +3,22 @@ use std::fs::File;
 use std::io;
 
 use wgpu_test_3::renderer::gltf::GLTF;
-use wgpu_test_3::run;
+use wgpu_test_3::{logger, run};
 
 fn main() -> io::Result<()> {
+    logger::init();
+
     let args: Vec<String> = env::args().collect();
     let path = args.get(1).map(String::as_str).unwrap_or("BoxInterleaved.glb");
     let mut file = File::open(path)?;
 
-    let gltf = GLTF::new(&mut file).unwrap();
+    let gltf = match GLTF::new(&mut file) {
+        Ok(gltf) => gltf,
+        Err(e) => {
+            log::error!("Failed to load {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
     run(gltf);
     
     Ok(())
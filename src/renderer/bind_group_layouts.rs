@@ -0,0 +1,2 @@
+// Bind group layouts and binding indices reflected from the shaders, see build.rs
+include!(concat!(env!("OUT_DIR"), "/bind_group_layouts.rs"));
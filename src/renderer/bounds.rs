@@ -0,0 +1,65 @@
+use cgmath::{Matrix4, Point3, Transform, Vector4};
+
+// axis aligned bounding box
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self { min: first, max: first }, |aabb, p| Self {
+            min: Point3::new(aabb.min.x.min(p.x), aabb.min.y.min(p.y), aabb.min.z.min(p.z)),
+            max: Point3::new(aabb.max.x.max(p.x), aabb.max.y.max(p.y), aabb.max.z.max(p.z)),
+        }))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::from_points([self.min, self.max, other.min, other.max]).unwrap()
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z), Point3::new(b.x, a.y, a.z),
+            Point3::new(a.x, b.y, a.z), Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z), Point3::new(b.x, a.y, b.z),
+            Point3::new(a.x, b.y, b.z), Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // bounds of the transformed box (not as tight as transforming the original points)
+    pub fn transform(&self, m: &Matrix4<f32>) -> Aabb {
+        Aabb::from_points(self.corners().map(|c| m.transform_point(c))).unwrap()
+    }
+}
+
+// view frustum as 6 inward facing planes (xyz normal, w distance), in the space the matrix transforms from
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // extracts the planes from a wgpu style (0..1 depth) view projection matrix
+    pub fn from_view_proj(m: &Matrix4<f32>) -> Self {
+        let row = |i: usize| Vector4::new(m.x[i], m.y[i], m.z[i], m.w[i]);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2];
+        Self { planes }
+    }
+
+    // conservative, boxes near the frustum corners may pass even though they're outside
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // corner furthest along the plane normal
+            let x = if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x };
+            let y = if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y };
+            let z = if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z };
+            plane.x * x + plane.y * y + plane.z * z + plane.w >= 0.0
+        })
+    }
+}
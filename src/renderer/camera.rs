@@ -1,16 +1,46 @@
-use cgmath::{Matrix3, Matrix4, Quaternion, Rotation3, SquareMatrix};
+use std::{cell::Cell, time::{Duration, Instant}};
+
+use cgmath::{InnerSpace, Matrix3, Matrix4, Quaternion, Rotation3, SquareMatrix, Vector3};
 use wgpu::util::DeviceExt;
 
+use super::{bind_group_layouts, bounds::Aabb, frame_globals::FrameGlobals, pipelines::post_processing::PostSettings};
+
 pub struct Camera {
     pub eye: cgmath::Point3<f32>,
     pub target: cgmath::Point3<f32>,
     pub up: cgmath::Vector3<f32>,
     pub aspect: f32,
     pub fovy: f32,
+    // the manual clip planes, auto fit leaves them alone, see clip_range
     pub znear: f32,
     pub zfar: f32,
     pub rot_x: cgmath::Deg<f32>,
     pub rot_y: cgmath::Deg<f32>,
+    pub post_settings: PostSettings,
+    pub clip_planes: ClipPlanes,
+    // (znear, zfar) from the last fit_clip_planes
+    fitted_planes: Option<(f32, f32)>,
+    // WGPU_TEST_STRICT_CAMERA=1 turns invalid camera warnings into panics
+    strict: bool,
+    last_warning: Cell<Option<Instant>>,
+}
+
+// largest zfar / znear, the depth buffer is a regular (not reversed) 32 bit float one
+const MAX_DEPTH_RATIO: f32 = 1e4;
+
+// ranges the camera parameters are clamped to before building matrices
+const MIN_FOVY: f32 = 1.0;
+const MAX_FOVY: f32 = 179.0;
+const MIN_ZNEAR: f32 = 1e-4;
+const INVALID_CAMERA_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, Debug)]
+pub enum ClipPlanes {
+    // znear and zfar are used as they are
+    Manual,
+    // the planes are fit to the scene bounds whenever the camera changes, znear and zfar are kept for Manual,
+    // margin is the fraction of the depth range added on both ends
+    AutoFit { min_znear: f32, margin: f32 },
 }
 
 pub struct CameraUniform {
@@ -24,6 +54,7 @@ pub struct CameraBinding {
     view_proj_buffer: wgpu::Buffer,
     position_buffer: wgpu::Buffer,
     inverse_view_proj_rot_buffer: wgpu::Buffer,
+    frame_globals_buffer: wgpu::Buffer,
 }
 
 impl Camera {
@@ -37,26 +68,133 @@ impl Camera {
         let zfar = 100.0f32;
         let rot_x = cgmath::Deg(0f32);
         let rot_y = cgmath::Deg(0f32);
+        let post_settings = PostSettings::default();
+        let clip_planes = ClipPlanes::Manual;
+        let strict = std::env::var("WGPU_TEST_STRICT_CAMERA").is_ok_and(|v| v == "1");
 
         Self {
-            eye, target, up, aspect, fovy, znear, zfar, rot_x, rot_y
+            eye, target, up, aspect, fovy, znear, zfar, rot_x, rot_y, post_settings, clip_planes,
+            fitted_planes: None, strict, last_warning: Cell::new(None),
         }
     }
 
-    pub fn to_camera_uniform(&self) -> CameraUniform {
+    fn report_invalid(&self, message: &str) {
+        if self.strict {
+            panic!("{}", message);
+        }
+        let now = Instant::now();
+        if self.last_warning.get().is_none_or(|last| now - last >= INVALID_CAMERA_WARNING_INTERVAL) {
+            log::warn!("{}", message);
+            self.last_warning.set(Some(now));
+        }
+    }
+
+    /*
+    * fovy, aspect, znear, zfar and up as they're used for the matrices, values that would produce
+    * NaNs (zero fov, znear <= 0, zfar <= znear, an up vector that is zero or parallel to the view) are replaced.
+    */
+    fn checked_projection(&self, eye: cgmath::Point3<f32>) -> (f32, f32, f32, f32, Vector3<f32>) {
+        let mut problems = vec![];
+
+        let fovy = if self.fovy.is_finite() { self.fovy.clamp(MIN_FOVY, MAX_FOVY) } else { 45.0 };
+        if fovy != self.fovy {
+            problems.push(format!("fovy {} -> {}", self.fovy, fovy));
+        }
+        let aspect = if self.aspect.is_finite() && self.aspect > 0.0 { self.aspect } else { 1.0 };
+        if aspect != self.aspect {
+            problems.push(format!("aspect {} -> {}", self.aspect, aspect));
+        }
+        let (requested_znear, requested_zfar) = self.clip_range();
+        let znear = if requested_znear.is_finite() { requested_znear.max(MIN_ZNEAR) } else { MIN_ZNEAR };
+        if znear != requested_znear {
+            problems.push(format!("znear {} -> {}", requested_znear, znear));
+        }
+        let zfar = if requested_zfar.is_finite() && requested_zfar > znear { requested_zfar } else { znear * MAX_DEPTH_RATIO };
+        if zfar != requested_zfar {
+            problems.push(format!("zfar {} -> {}", requested_zfar, zfar));
+        }
+
+        let forward = self.target - eye;
+        let usable_up = |up: Vector3<f32>| up.magnitude2().is_finite() && forward.cross(up).magnitude2() > 1e-12;
+        let up = if usable_up(self.up) {
+            self.up.normalize()
+        } else {
+            let replacement = if usable_up(Vector3::unit_y()) { Vector3::unit_y() } else { Vector3::unit_z() };
+            problems.push(format!("up {:?} -> {:?}", self.up, replacement));
+            replacement
+        };
+
+        if !problems.is_empty() {
+            self.report_invalid(&format!("invalid camera parameters: {}", problems.join(", ")));
+        }
+        (fovy, aspect, znear, zfar, up)
+    }
+
+    // None (after a warning) when the matrices can't be built, e.g. eye == target
+    pub fn checked_camera_uniform(&self) -> Option<CameraUniform> {
+        let uniform = self.to_camera_uniform();
+        let finite = |m: &[[f32; 4]; 4]| m.iter().flatten().all(|v| v.is_finite());
+        if finite(&uniform.view_proj) && finite(&uniform.inverse_view_proj_rot) && uniform.position.iter().all(|v| v.is_finite()) {
+            Some(uniform)
+        } else {
+            self.report_invalid(&format!("camera matrices aren't finite (eye {:?}, target {:?}), keeping the last camera", self.eye, self.target));
+            None
+        }
+    }
+
+    fn eye_rotated(&self) -> cgmath::Point3<f32> {
         let rot =
               Quaternion::from_angle_y(self.rot_x)
             * Quaternion::from_angle_x(self.rot_y);
-        let eye_rotated = cgmath::Transform::transform_point(&cgmath::Matrix4::from(rot), self.eye);
-        let view = cgmath::Matrix4::look_at_rh(eye_rotated, self.target, self.up);
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+        cgmath::Transform::transform_point(&cgmath::Matrix4::from(rot), self.eye)
+    }
+
+    // the clip planes in use: the fitted ones in auto fit mode (once fit), otherwise znear and zfar
+    pub fn clip_range(&self) -> (f32, f32) {
+        match (self.clip_planes, self.fitted_planes) {
+            (ClipPlanes::AutoFit { .. }, Some(fitted)) => fitted,
+            _ => (self.znear, self.zfar),
+        }
+    }
+
+    /*
+    * In auto fit mode, fits the clip planes to cover the scene bounds as seen from the current position.
+    * Does nothing in manual mode, or when the whole scene is behind the camera (the last fit is kept).
+    */
+    pub fn fit_clip_planes(&mut self, bounds: &Aabb) {
+        let (min_znear, margin) = match self.clip_planes {
+            ClipPlanes::Manual => return,
+            ClipPlanes::AutoFit { min_znear, margin } => (min_znear, margin),
+        };
+        let view = cgmath::Matrix4::look_at_rh(self.eye_rotated(), self.target, self.up);
+        // view space looks down -z
+        let depths = bounds.corners().map(|c| -cgmath::Transform::transform_point(&view, c).z);
+        let nearest = depths.iter().copied().fold(f32::MAX, f32::min);
+        let farthest = depths.iter().copied().fold(f32::MIN, f32::max);
+        if farthest <= 0.0 {
+            return;
+        }
+
+        let range = farthest - nearest.max(0.0);
+        let zfar = farthest + range * margin;
+        let znear = (nearest - range * margin).max(min_znear).max(zfar / MAX_DEPTH_RATIO);
+        // a flat scene seen edge on has no depth range, keep the planes apart
+        self.fitted_planes = Some((znear, zfar.max(znear * 2.0)));
+    }
+
+    pub fn to_camera_uniform(&self) -> CameraUniform {
+        let eye_rotated = self.eye_rotated();
+        let (fovy, aspect, znear, zfar, up) = self.checked_projection(eye_rotated);
+        let view = cgmath::Matrix4::look_at_rh(eye_rotated, self.target, up);
+        let proj = cgmath::perspective(cgmath::Deg(fovy), aspect, znear, zfar);
         let view_proj = super::wgpu_context::OPENGL_TO_WGPU_MATRIX * proj * view;
         let m = view_proj;
+        // not invertible when eye == target, checked_camera_uniform catches the NaNs
         let m3 = Matrix3::new(
             m.x.x, m.x.y, m.x.z,
             m.y.x, m.y.y, m.y.z,
             m.z.x, m.z.y, m.z.z,
-        ).invert().unwrap();
+        ).invert().unwrap_or(Matrix3::from_value(f32::NAN));
         let inverse_view_proj_rot = Matrix4::new(
             m3.x.x, m3.x.y, m3.x.z, 0.0,
             m3.y.x, m3.y.y, m3.y.z, 0.0,
@@ -128,62 +266,42 @@ impl CameraUniform {
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
+        let frame_globals_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Frame Globals Buffer"),
+                contents: bytemuck::bytes_of(&<FrameGlobals as bytemuck::Zeroable>::zeroed()),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
-                    binding: 0,
+                    binding: bind_group_layouts::camera::VIEW_PROJ,
                     resource: view_proj_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 1,
+                    binding: bind_group_layouts::camera::CAMERA_POSITION,
                     resource: position_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 2,
+                    binding: bind_group_layouts::camera::INVERSE_VIEW_PROJ_ROT,
                     resource: inverse_view_proj_rot_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: bind_group_layouts::camera::FRAME_GLOBALS,
+                    resource: frame_globals_buffer.as_entire_binding(),
+                },
             ],
             label: Some("Camera Bind Group"),
         });
 
-        CameraBinding { bind_group, view_proj_buffer, position_buffer, inverse_view_proj_rot_buffer }
+        CameraBinding { bind_group, view_proj_buffer, position_buffer, inverse_view_proj_rot_buffer, frame_globals_buffer }
     }
 
     pub fn desc() -> wgpu::BindGroupLayoutDescriptor<'static> {
         wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
+            entries: bind_group_layouts::camera::ENTRIES,
             label: Some("Camera Bind Group Layout")
         }
     }
@@ -195,5 +313,60 @@ impl CameraBinding {
         queue.write_buffer(&self.position_buffer, 0, bytemuck::cast_slice(&camera.position));
         queue.write_buffer(&self.inverse_view_proj_rot_buffer, 0, bytemuck::cast_slice(&camera.inverse_view_proj_rot));
     }
+
+    pub fn update_frame_globals(&self, frame_globals: &FrameGlobals, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.frame_globals_buffer, 0, bytemuck::bytes_of(frame_globals));
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Point3;
+
+    fn camera() -> Camera {
+        Camera::new(&wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            width: 800,
+            height: 600,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        })
+    }
+
+    #[test]
+    fn auto_fit_tracks_a_growing_scene() {
+        let mut camera = camera();
+        camera.eye = Point3::new(0.0, 0.0, 20.0);
+        camera.clip_planes = ClipPlanes::AutoFit { min_znear: 0.01, margin: 0.05 };
+        for size in [10.0, 100.0, 1000.0, 10000.0] {
+            let half = size / 2.0;
+            let bounds = Aabb { min: Point3::new(-half, -half, -half), max: Point3::new(half, half, half) };
+            camera.fit_clip_planes(&bounds);
+            let (znear, zfar) = camera.clip_range();
+            assert!(znear > 0.0 && znear < zfar, "size {}: planes {} {}", size, znear, zfar);
+            // the farthest corner (the far face of the box) is inside the far plane
+            assert!(zfar >= 20.0 + half, "size {}: zfar {}", size, zfar);
+            // a box the camera is outside of starts past the near plane
+            if half < 20.0 {
+                assert!(znear <= 20.0 - half, "size {}: znear {}", size, znear);
+            }
+            assert!(zfar / znear <= MAX_DEPTH_RATIO * 1.001, "size {}: ratio {}", size, zfar / znear);
+        }
+    }
+
+    #[test]
+    fn manual_planes_survive_auto_fit() {
+        let mut camera = camera();
+        let manual = (camera.znear, camera.zfar);
+        camera.clip_planes = ClipPlanes::AutoFit { min_znear: 0.01, margin: 0.05 };
+        camera.fit_clip_planes(&Aabb { min: Point3::new(-500.0, -500.0, -500.0), max: Point3::new(500.0, 500.0, 500.0) });
+        assert_ne!(camera.clip_range(), manual);
+        camera.clip_planes = ClipPlanes::Manual;
+        assert_eq!(camera.clip_range(), manual);
+    }
+}
@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use wgpu::util::DeviceExt;
+
+// resolution of the neutral lut used when no grade is loaded
+const IDENTITY_LUT_SIZE: u32 = 32;
+
+/*
+* A 3d color lookup table, red varies fastest, then green, then blue (the .cube order)
+*/
+pub struct ColorLut {
+    pub size: u32,
+    pub data: Vec<[f32; 3]>,
+    // input range, .cube DOMAIN_MIN / DOMAIN_MAX
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    // authored against srgb encoded input (most grading tools), false for luts that expect linear values
+    pub srgb: bool,
+}
+
+impl ColorLut {
+    pub fn identity(size: u32) -> Self {
+        let max = (size - 1) as f32;
+        let data = (0..size * size * size).map(|i| {
+            [(i % size) as f32 / max, (i / size % size) as f32 / max, (i / (size * size)) as f32 / max]
+        }).collect();
+        Self { size, data, domain_min: [0.0; 3], domain_max: [1.0; 3], srgb: true }
+    }
+
+    // .cube (adobe / resolve) or a png strip of size slices, picked by the extension
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let lut = match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("cube") => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Self::from_cube(&text)
+            },
+            _ => {
+                let img = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Self::from_strip(&img)
+            },
+        };
+        lut.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_cube(text: &str) -> Result<Self, String> {
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap();
+            let parse_rgb = |words: &mut dyn Iterator<Item = &str>| -> Result<[f32; 3], String> {
+                let values = words.map(|w| w.parse::<f32>()).collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("line {}: {}", line_idx + 1, e))?;
+                <[f32; 3]>::try_from(values).map_err(|_| format!("line {}: expected 3 values", line_idx + 1))
+            };
+            match keyword {
+                "LUT_3D_SIZE" => {
+                    let n = words.next().and_then(|w| w.parse::<u32>().ok())
+                        .ok_or(format!("line {}: invalid LUT_3D_SIZE", line_idx + 1))?;
+                    size = Some(n);
+                },
+                "LUT_1D_SIZE" => return Err("1d luts are not supported".to_string()),
+                "DOMAIN_MIN" => domain_min = parse_rgb(&mut words)?,
+                "DOMAIN_MAX" => domain_max = parse_rgb(&mut words)?,
+                _ => data.push(parse_rgb(&mut line.split_whitespace())?),
+            }
+        }
+        let size = size.ok_or("missing LUT_3D_SIZE")?;
+        Self::validated(Self { size, data, domain_min, domain_max, srgb: true })
+    }
+
+    /*
+    * A horizontal strip of size slices of size x size texels, blue selects the slice,
+    * red goes along x and green along y (the common unreal / unity layout)
+    */
+    pub fn from_strip(img: &image::DynamicImage) -> Result<Self, String> {
+        let size = img.height();
+        if img.width() != size * size {
+            return Err(format!("expected a {}x{} strip, got {}x{}", size * size, size, img.width(), img.height()));
+        }
+        let img = img.to_rgb32f();
+        let data = (0..size * size * size).map(|i| {
+            let (r, g, b) = (i % size, i / size % size, i / (size * size));
+            img.get_pixel(b * size + r, g).0
+        }).collect();
+        Self::validated(Self { size, data, domain_min: [0.0; 3], domain_max: [1.0; 3], srgb: true })
+    }
+
+    fn validated(self) -> Result<Self, String> {
+        if !(2..=256).contains(&self.size) {
+            return Err(format!("unsupported lut size {}", self.size));
+        }
+        let expected = (self.size * self.size * self.size) as usize;
+        if self.data.len() != expected {
+            return Err(format!("expected {} entries for size {}, got {}", expected, self.size, self.data.len()));
+        }
+        if (0..3).any(|c| self.domain_max[c] <= self.domain_min[c]) {
+            return Err("empty domain".to_string());
+        }
+        Ok(self)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ColorLutParams {
+    domain_min: [f32; 3],
+    size: f32,
+    domain_max: [f32; 3],
+    srgb: u32,
+}
+
+pub struct ColorLutTexture {
+    _texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub params_buffer: wgpu::Buffer,
+}
+
+impl ColorLutTexture {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, lut: &ColorLut) -> Self {
+        let size = wgpu::Extent3d { width: lut.size, height: lut.size, depth_or_array_layers: lut.size };
+        // 8 bits per channel is plenty for a grade, the values are display referred
+        let texels: Vec<u8> = lut.data.iter()
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.0].map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8))
+            .collect();
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Color Lut Texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &texels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * lut.size),
+                rows_per_image: Some(lut.size),
+            },
+            size,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Color Lut Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let params = ColorLutParams {
+            domain_min: lut.domain_min,
+            size: lut.size as f32,
+            domain_max: lut.domain_max,
+            srgb: lut.srgb as u32,
+        };
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Color Lut Params Buffer"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        Self { _texture: texture, view, sampler, params_buffer }
+    }
+
+    /*
+    * The lut from WGPU_TEST_LUT (a .cube or png strip), WGPU_TEST_LUT_LINEAR=1 if it expects linear input.
+    * Falls back to the identity lut, which returns the color it's given.
+    */
+    pub fn from_env(device: &wgpu::Device, queue: &wgpu::Queue) -> (Self, bool) {
+        let lut = std::env::var("WGPU_TEST_LUT").ok().and_then(|path| {
+            match ColorLut::load(Path::new(&path)) {
+                Ok(mut lut) => {
+                    lut.srgb = !std::env::var("WGPU_TEST_LUT_LINEAR").is_ok_and(|v| v == "1");
+                    log::info!("color lut {} ({}^3)", path, lut.size);
+                    Some(lut)
+                },
+                Err(e) => {
+                    log::error!("color lut: {}", e);
+                    None
+                },
+            }
+        });
+        let loaded = lut.is_some();
+        let lut = lut.unwrap_or_else(|| ColorLut::identity(IDENTITY_LUT_SIZE));
+        (Self::new(device, queue, &lut), loaded)
+    }
+}
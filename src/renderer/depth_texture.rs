@@ -1,6 +1,9 @@
 pub struct DepthTexture {
+    // kept alive for the view
+    #[allow(dead_code)]
     texture: wgpu::Texture,
     pub view: wgpu::TextureView,
+    #[allow(dead_code)]
     sampler: wgpu::Sampler,
 }
 
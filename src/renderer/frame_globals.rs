@@ -0,0 +1,48 @@
+use std::time::Instant;
+
+/*
+* Per frame values shared by every pipeline that draws to the screen (pbr, skybox, post processing).
+* They live in binding 3 of the camera bind group, since the pbr pipeline already uses all 4 bind
+* groups the downlevel limits allow. Post processing binds the camera group as group 1.
+* The wgsl side is shaders/frame_globals.wgsl, which the shaders #include (see shader_source).
+*/
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FrameGlobals {
+    // seconds since the renderer was created
+    pub time: f32,
+    pub delta_time: f32,
+    // the logger's frame counter, so log lines and shader debugging agree on the frame
+    pub frame_index: u32,
+    _padding: u32,
+    pub viewport_size: [f32; 2],
+    // room for things like taa jitter without changing the layout
+    pub spare: [f32; 2],
+}
+
+pub struct FrameClock {
+    start: Instant,
+    last_frame: Instant,
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self { start: now, last_frame: now }
+    }
+
+    pub fn tick(&mut self, viewport_size: [f32; 2]) -> FrameGlobals {
+        let now = Instant::now();
+        let globals = FrameGlobals {
+            time: (now - self.start).as_secs_f32(),
+            delta_time: (now - self.last_frame).as_secs_f32(),
+            // wraps after 2^32 frames like a u32 counter would
+            frame_index: crate::logger::frame() as u32,
+            _padding: 0,
+            viewport_size,
+            spare: [0.0; 2],
+        };
+        self.last_frame = now;
+        globals
+    }
+}
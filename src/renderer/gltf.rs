@@ -1,12 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Read};
-use cgmath::{Matrix, Matrix3, Matrix4, Quaternion, SquareMatrix};
+use cgmath::{Matrix, Matrix3, Matrix4, Quaternion, SquareMatrix, Transform};
 
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use super::lights::{PunctualLight, PunctualLightKind};
 use super::pipelines::pbr;
+use super::uv_analysis;
 
 fn buffer_to_ascii(buffer: &[u8]) -> String {
     buffer.iter().map(|&x| x as char).collect()
@@ -45,6 +47,16 @@ pub enum AccessorType {
     Mat4,
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum AlphaMode {
+    #[serde(rename = "OPAQUE")]
+    Opaque,
+    #[serde(rename = "MASK")]
+    Mask,
+    #[serde(rename = "BLEND")]
+    Blend,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MimeType {
     #[serde(rename = "image/png")]
@@ -92,6 +104,14 @@ impl SamplerMinFilterType {
             SamplerMinFilterType::NearestMipmapNearest => wgpu::FilterMode::Nearest,
         }
     }
+
+    fn to_wgpu_mipmap_filter_mode(&self) -> Option<wgpu::FilterMode> {
+        match *self {
+            SamplerMinFilterType::Linear | SamplerMinFilterType::Nearest => None,
+            SamplerMinFilterType::LinearMipmapLinear | SamplerMinFilterType::NearestMipmapLinear => Some(wgpu::FilterMode::Linear),
+            SamplerMinFilterType::LinearMipmapNearest | SamplerMinFilterType::NearestMipmapNearest => Some(wgpu::FilterMode::Nearest),
+        }
+    }
 }
 
 #[derive(Serialize_repr, Deserialize_repr, Debug)]
@@ -129,7 +149,7 @@ pub struct Accessor {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Asset {
-    pub generator: String,
+    pub generator: Option<String>,
     pub version: String,
 }
 
@@ -190,11 +210,55 @@ pub struct Mesh {
     pub primitives: Vec<Primitive>,
 }
 
+fn default_uv_offset() -> [f32; 2] { [0.0, 0.0] }
+fn default_uv_scale() -> [f32; 2] { [1.0, 1.0] }
+
+/*
+* KHR_texture_transform
+* uv' = translation * rotation * scale * uv, rotation is in radians counter clockwise
+*/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TextureTransform {
+    #[serde(default = "default_uv_offset")]
+    pub offset: [f32; 2],
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(default = "default_uv_scale")]
+    pub scale: [f32; 2],
+    // overrides the texture info's texCoord
+    #[serde(rename = "texCoord")]
+    pub tex_coord: Option<usize>,
+}
+
+impl TextureTransform {
+    pub fn apply(&self, uv: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = self.rotation.sin_cos();
+        let (u, v) = (uv[0] * self.scale[0], uv[1] * self.scale[1]);
+        [
+            cos * u + sin * v + self.offset[0],
+            -sin * u + cos * v + self.offset[1],
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TextureInfoExtensions {
+    #[serde(rename = "KHR_texture_transform")]
+    pub khr_texture_transform: Option<TextureTransform>,
+}
+
+// the TEXCOORD_n a texture reads, and the transform applied to it
+fn uv_set(tex_coord: usize, extensions: &Option<TextureInfoExtensions>) -> (usize, Option<&TextureTransform>) {
+    let transform = extensions.as_ref().and_then(|e| e.khr_texture_transform.as_ref());
+    (transform.and_then(|t| t.tex_coord).unwrap_or(tex_coord), transform)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BaseColorTexture {
     pub index: usize,
     #[serde(rename = "texCoord", default = "default_tex_coord")]
     pub tex_coord: usize,
+    pub extensions: Option<TextureInfoExtensions>,
 }
 
 /*
@@ -206,6 +270,7 @@ pub struct MetallicRoughnessTexture {
     pub index: usize,
     #[serde(rename = "texCoord", default = "default_tex_coord")]
     pub tex_coord: usize,
+    pub extensions: Option<TextureInfoExtensions>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -231,7 +296,8 @@ pub struct NormalTextureInfo {
     pub tex_coord: usize,
     #[serde(default = "default_scale")]
     pub scale: f32,
-    //extensions, extras ..
+    pub extensions: Option<TextureInfoExtensions>,
+    // extras ..
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -241,7 +307,8 @@ pub struct OcclusionTextureInfo {
     pub tex_coord: usize,
     #[serde(default = "default_strength")]
     pub strength: u64,
-    //extensions, extras ..
+    pub extensions: Option<TextureInfoExtensions>,
+    // extras ..
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -249,7 +316,8 @@ pub struct EmissiveTextureInfo {
     pub index: usize,
     #[serde(rename = "texCoord", default = "default_tex_coord")]
     pub tex_coord: usize,
-    //extensions, extras ..
+    pub extensions: Option<TextureInfoExtensions>,
+    // extras ..
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -265,7 +333,12 @@ pub struct Material {
     pub emissive_texture: Option<EmissiveTextureInfo>,
     #[serde(rename = "emissiveFactor")]
     pub emissive_factor: Option<[f64; 3]>,
-    // .. alpha cutoff, double sided, name, extension, extras
+    // only picks the render queue, cutout and blending aren't implemented
+    #[serde(rename = "alphaMode")]
+    pub alpha_mode: Option<AlphaMode>,
+    // engine specific settings are custom properties here: shadow_depth_bias, shadow_normal_bias
+    pub extras: Option<serde_json::Value>,
+    // .. alpha cutoff, double sided, name, extension
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -277,6 +350,49 @@ pub struct Node {
     pub scale: Option<[f64; 3]>,
     pub matrix: Option<[f64; 16]>,
     pub children: Option<Vec<usize>>,
+    pub extensions: Option<NodeExtensions>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    pub khr_lights_punctual: Option<NodeLight>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeLight {
+    pub light: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SceneExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    pub khr_lights_punctual: Option<LightsPunctual>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LightsPunctual {
+    pub lights: Vec<Light>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Light {
+    pub name: Option<String>,
+    // "directional", "point" or "spot"
+    #[serde(rename = "type")]
+    pub light_type: String,
+    pub color: Option<[f64; 3]>,
+    pub intensity: Option<f64>,
+    pub range: Option<f64>,
+    pub spot: Option<Spot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Spot {
+    #[serde(rename = "innerConeAngle")]
+    pub inner_cone_angle: Option<f64>,
+    #[serde(rename = "outerConeAngle")]
+    pub outer_cone_angle: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -314,19 +430,26 @@ pub struct Image {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SceneDescription {
+    // everything except asset is optional in gltf, e.g. material or animation libraries have no meshes or scenes
+    #[serde(default)]
     pub accessors: Vec<Accessor>,
     pub asset: Asset,
-    #[serde(rename = "bufferViews")]
+    #[serde(rename = "bufferViews", default)]
     pub buffer_views: Vec<BufferView>,
+    #[serde(default)]
     pub buffers: Vec<Buffer>,
+    #[serde(default)]
     pub meshes: Vec<Mesh>,
+    #[serde(default)]
     pub nodes: Vec<Node>,
-    pub scene: usize,
+    pub scene: Option<usize>,
+    #[serde(default)]
     pub scenes: Vec<Scene>,
     pub materials: Option<Vec<Material>>,
     pub textures: Option<Vec<Texture>>,
     pub images: Option<Vec<Image>>,
     pub samplers: Option<Vec<Sampler>>,
+    pub extensions: Option<SceneExtensions>,
 }
 
 pub struct JSONChunk {
@@ -340,6 +463,111 @@ pub struct AccessorDataElement<'a> {
     pub stride: u64,
 }
 
+#[derive(Debug)]
+pub enum GltfError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    // material, texture or animation only files
+    NoMeshes,
+    // the scene has nodes but none of them (or their children) has a mesh
+    EmptyScene,
+    // normal and tangent generation aren't implemented
+    MissingNormals { mesh: usize, primitive: usize },
+    MissingTangents { mesh: usize, primitive: usize },
+    InvalidIndexComponentType { mesh: usize, primitive: usize },
+    // a primitive refers to an accessor or material past the end of the file's lists
+    MissingAccessor { mesh: usize, primitive: usize, accessor: usize },
+    MissingMaterial { mesh: usize, primitive: usize, material: usize },
+    // only png / jpeg images embedded in the binary chunk are supported
+    UnsupportedImage { image: usize },
+}
+
+impl std::fmt::Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GltfError::Io(e) => write!(f, "GLTF: {}", e),
+            GltfError::Json(e) => write!(f, "GLTF: invalid json: {}", e),
+            GltfError::NoMeshes => write!(f, "GLTF: file has no meshes, material, texture or animation only files can't be viewed"),
+            GltfError::EmptyScene => write!(f, "GLTF: the scene doesn't reference any meshes, nothing to render"),
+            GltfError::MissingNormals { mesh, primitive } =>
+                write!(f, "GLTF: mesh {} primitive {} has no vertex normals, normal generation isn't implemented", mesh, primitive),
+            GltfError::MissingTangents { mesh, primitive } =>
+                write!(f, "GLTF: mesh {} primitive {} has a normal map but no tangents, tangent generation isn't implemented", mesh, primitive),
+            GltfError::InvalidIndexComponentType { mesh, primitive } =>
+                write!(f, "GLTF: mesh {} primitive {} has indices that aren't unsigned integers", mesh, primitive),
+            GltfError::MissingAccessor { mesh, primitive, accessor } =>
+                write!(f, "GLTF: mesh {} primitive {} uses accessor {}, which doesn't exist", mesh, primitive, accessor),
+            GltfError::MissingMaterial { mesh, primitive, material } =>
+                write!(f, "GLTF: mesh {} primitive {} uses material {}, which doesn't exist", mesh, primitive, material),
+            GltfError::UnsupportedImage { image } =>
+                write!(f, "GLTF: image {} isn't a png or jpeg in a buffer view (uris aren't supported)", image),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+impl From<io::Error> for GltfError {
+    fn from(e: io::Error) -> Self {
+        GltfError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GltfError {
+    fn from(e: serde_json::Error) -> Self {
+        GltfError::Json(e)
+    }
+}
+
+/*
+* Checks everything to_pbr_meshes relies on up front, so a bad file is reported
+* before a window is opened instead of panicking halfway through the upload.
+*/
+fn validate(scene: &SceneDescription) -> Result<(), GltfError> {
+    if scene.meshes.is_empty() {
+        return Err(GltfError::NoMeshes);
+    }
+    if scene_to_mesh_instances(scene).is_empty() {
+        return Err(GltfError::EmptyScene);
+    }
+    for (mesh_idx, mesh) in scene.meshes.iter().enumerate() {
+        for (primitive_idx, primitive) in mesh.primitives.iter().enumerate() {
+            if primitive.attributes.normal.is_none() {
+                return Err(GltfError::MissingNormals { mesh: mesh_idx, primitive: primitive_idx });
+            }
+            let attributes = &primitive.attributes;
+            let accessors = [Some(attributes.position), attributes.normal, attributes.tangent, Some(primitive.indices)];
+            let texture_coords = attributes.additional_fields.iter()
+                .filter(|(name, _)| name.starts_with("TEXCOORD_"))
+                .map(|(_, accessor)| Some(*accessor));
+            for accessor in accessors.into_iter().chain(texture_coords).flatten() {
+                if accessor >= scene.accessors.len() {
+                    return Err(GltfError::MissingAccessor { mesh: mesh_idx, primitive: primitive_idx, accessor });
+                }
+            }
+            let material = match primitive.material {
+                Some(mat_idx) => Some(scene.materials.as_ref().and_then(|mats| mats.get(mat_idx))
+                    .ok_or(GltfError::MissingMaterial { mesh: mesh_idx, primitive: primitive_idx, material: mat_idx })?),
+                None => None,
+            };
+            let has_normal_map = material.and_then(|mat| mat.normal_texture.as_ref()).is_some();
+            if has_normal_map && primitive.attributes.tangent.is_none() {
+                return Err(GltfError::MissingTangents { mesh: mesh_idx, primitive: primitive_idx });
+            }
+            match scene.accessors[primitive.indices].component_type {
+                ComponentType::UnsignedByte | ComponentType::UnsignedShort | ComponentType::UnsignedInt => {},
+                _ => return Err(GltfError::InvalidIndexComponentType { mesh: mesh_idx, primitive: primitive_idx }),
+            }
+        }
+    }
+    for (image_idx, image) in scene.images.iter().flatten().enumerate() {
+        if image.mime_type.is_none() || image.buffer_view.is_none() {
+            return Err(GltfError::UnsupportedImage { image: image_idx });
+        }
+    }
+    Ok(())
+}
+
 pub struct GLTF {
     pub magic: String,
     pub version: u32,
@@ -373,9 +601,8 @@ pub fn get_accessor_component_size(accessor: &Accessor) -> u8 {
     }
 }
 
-fn construct_mesh_instances_map(scene: &SceneDescription, node_idx: usize, mut transform: Matrix4<f32>, acc: &mut HashMap<usize, Vec<pbr::Instance>>) {
-    let node = &scene.nodes[node_idx];
-
+// the node's world transform, given its parent's
+fn node_transform(node: &Node, mut transform: Matrix4<f32>) -> Matrix4<f32> {
     if let Some(v) = node.scale {
         transform = transform * Matrix4::from_nonuniform_scale(v[0] as f32, v[1] as f32, v[2] as f32);
     }
@@ -395,8 +622,15 @@ fn construct_mesh_instances_map(scene: &SceneDescription, node_idx: usize, mut t
         );
         transform = transform * m;
     }
+    transform
+}
+
+// instances are paired with the name of the node they came from
+fn construct_mesh_instances_map(scene: &SceneDescription, node_idx: usize, transform: Matrix4<f32>, acc: &mut HashMap<usize, Vec<(pbr::Instance, Option<String>)>>) {
+    let node = &scene.nodes[node_idx];
+    let transform = node_transform(node, transform);
     if let Some(mesh) = node.mesh {
-        acc.entry(mesh as usize).or_insert(Vec::new()).push(
+        acc.entry(mesh).or_insert(Vec::new()).push((
             pbr::Instance::from(
                 transform.clone(),
                 Matrix3::new(
@@ -404,8 +638,9 @@ fn construct_mesh_instances_map(scene: &SceneDescription, node_idx: usize, mut t
                     transform.y.x, transform.y.y, transform.y.z,
                     transform.z.x, transform.z.y, transform.z.z,
                 ).invert().unwrap().transpose(),
-            )
-        );
+            ),
+            node.name.clone(),
+        ));
     }
     if let Some(children) = &node.children {
         for child_idx in children {
@@ -414,19 +649,75 @@ fn construct_mesh_instances_map(scene: &SceneDescription, node_idx: usize, mut t
     }
 }
 
-fn scene_to_mesh_instances(scene: &SceneDescription) -> HashMap<usize, Vec<pbr::Instance>> {
-    let mut map: HashMap<usize, Vec<pbr::Instance>> = HashMap::new();
+/*
+* Without a default scene the first scene is used, and without any scenes every node that isn't
+* somebody's child is treated as a root (the spec leaves both cases up to the implementation).
+*/
+fn root_nodes(scene: &SceneDescription) -> Vec<usize> {
+    match scene.scene.or(if scene.scenes.is_empty() { None } else { Some(0) }) {
+        Some(scene_idx) => scene.scenes[scene_idx].nodes.clone(),
+        None => {
+            let children: HashSet<usize> = scene.nodes.iter()
+                .flat_map(|node| node.children.iter().flatten().copied())
+                .collect();
+            (0..scene.nodes.len()).filter(|idx| !children.contains(idx)).collect()
+        }
+    }
+}
+
+fn scene_to_mesh_instances(scene: &SceneDescription) -> HashMap<usize, Vec<(pbr::Instance, Option<String>)>> {
+    let mut map: HashMap<usize, Vec<(pbr::Instance, Option<String>)>> = HashMap::new();
     let transform = Matrix4::identity();
 
     // Only rendering the main scene for now
-    let scene_nodes = &scene.scenes[scene.scene].nodes;
-    for node_idx in scene_nodes {
-        construct_mesh_instances_map(scene, *node_idx, transform, &mut map);
+    for node_idx in root_nodes(scene) {
+        construct_mesh_instances_map(scene, node_idx, transform, &mut map);
     }
 
     map
 }
 
+fn collect_punctual_lights(scene: &SceneDescription, lights: &[Light], node_idx: usize, transform: Matrix4<f32>, acc: &mut Vec<PunctualLight>) {
+    let node = &scene.nodes[node_idx];
+    let transform = node_transform(node, transform);
+    if let Some(node_light) = node.extensions.as_ref().and_then(|e| e.khr_lights_punctual.as_ref()) {
+        match lights.get(node_light.light) {
+            Some(light) => {
+                let kind = match light.light_type.as_str() {
+                    "point" => Some(PunctualLightKind::Point),
+                    "spot" => {
+                        let spot = light.spot.as_ref();
+                        Some(PunctualLightKind::Spot {
+                            inner_cone: cgmath::Rad(spot.and_then(|s| s.inner_cone_angle).unwrap_or(0.0) as f32),
+                            outer_cone: cgmath::Rad(spot.and_then(|s| s.outer_cone_angle).unwrap_or(std::f64::consts::FRAC_PI_4) as f32),
+                        })
+                    },
+                    // the sun comes from Lights
+                    other => {
+                        log::info!("GLTF: ignoring {} light on node {}", other, node_idx);
+                        None
+                    },
+                };
+                if let Some(kind) = kind {
+                    acc.push(PunctualLight {
+                        kind,
+                        position: transform.transform_point(cgmath::Point3::new(0.0, 0.0, 0.0)),
+                        // lights point down their local -z
+                        direction: transform.transform_vector(-cgmath::Vector3::unit_z()),
+                        color: light.color.unwrap_or([1.0; 3]).map(|c| c as f32),
+                        intensity: light.intensity.unwrap_or(1.0) as f32,
+                        range: light.range.map(|r| r as f32),
+                    });
+                }
+            },
+            None => log::warn!("GLTF: node {} references missing light {}", node_idx, node_light.light),
+        }
+    }
+    for child_idx in node.children.iter().flatten() {
+        collect_punctual_lights(scene, lights, *child_idx, transform, acc);
+    }
+}
+
 fn set_alpha_channel(image: &mut image::DynamicImage, alpha: u8) {
     let mut rgba_image = image.to_rgba8();
     
@@ -438,7 +729,7 @@ fn set_alpha_channel(image: &mut image::DynamicImage, alpha: u8) {
 }
 
 impl GLTF {
-    pub fn new(file: &mut File) -> io::Result<Self> {
+    pub fn new(file: &mut File) -> Result<Self, GltfError> {
         let mut magic_buffer = [0u8; 4];
         file.read_exact(&mut magic_buffer)?;
         let magic = buffer_to_ascii(&magic_buffer);
@@ -453,9 +744,11 @@ impl GLTF {
 
         let json_chunk = GLTF::parse_json_chunk(file)?;
         let binary_buffer = GLTF::parse_binary_buffer(file)?;
-        let scene = serde_json::from_str(&json_chunk.chunk_data)?;
-        println!("{:#?}", scene);
-        println!("{}", json_chunk.chunk_data);
+        let scene: SceneDescription = serde_json::from_str(&json_chunk.chunk_data)?;
+        log::debug!("{:#?}", scene);
+        log::trace!("{}", json_chunk.chunk_data);
+
+        validate(&scene)?;
 
         Ok(
             Self {
@@ -482,7 +775,11 @@ impl GLTF {
 
     fn parse_binary_buffer(file: &mut File) -> io::Result<Vec<u8>> {
         let mut length_buffer = [0u8; 4];
-        file.read_exact(&mut length_buffer)?;
+        // the binary chunk is optional, files without buffers end after the json chunk
+        match file.read_exact(&mut length_buffer) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(vec![]),
+            result => result?,
+        }
         let chunk_length = u32::from_le_bytes(length_buffer);
 
         let mut type_buffer = [0u8; 4];
@@ -549,13 +846,11 @@ impl GLTF {
                 )
             },
             ComponentType::UnsignedInt => {
-                pbr::VertexIndices::U32(
-                    self.accessor_to_contiguous_array(accessor_idx, |buf| {
-                        bytemuck::cast::<[u8; 4], u32>(buf[0..4].try_into().unwrap())
-                    })
-                )
+                pbr::VertexIndices::narrowed(self.accessor_to_contiguous_array(accessor_idx, |buf| {
+                    bytemuck::cast::<[u8; 4], u32>(buf[0..4].try_into().unwrap())
+                }))
             },
-            _ => { panic!("GLTF: Illegal vertex index component type.") },
+            _ => unreachable!("index component types are checked in GLTF::new"),
         }
     }
 
@@ -607,60 +902,25 @@ impl GLTF {
 
         let normal_tex_coords = maybe_material
             .and_then(|mat| mat.normal_texture.as_ref())
-            .and_then(|nt| primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", nt.tex_coord)))
-            .map(|n| {
-                self.accessor_to_contiguous_array(*n, |buf| {
-                    let s: &[u8; 8] = buf[0..8].try_into().unwrap();
-                    let res: [f32; 2] = bytemuck::cast(*s);
-                    res
-                })
-            });
+            .and_then(|nt| self.read_transformed_tex_coords(primitive, nt.tex_coord, &nt.extensions));
 
         let occlusion_tex_coords = maybe_material
             .and_then(|mat| mat.occlusion_texture.as_ref())
-            .and_then(|ot| primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", ot.tex_coord)))
-            .map(|n| {
-                self.accessor_to_contiguous_array(*n, |buf| {
-                    let s: &[u8; 8] = buf[0..8].try_into().unwrap();
-                    let res: [f32; 2] = bytemuck::cast(*s);
-                    res
-                })
-            });
+            .and_then(|ot| self.read_transformed_tex_coords(primitive, ot.tex_coord, &ot.extensions));
 
         let emissive_tex_coords = maybe_material
             .and_then(|mat| mat.emissive_texture.as_ref())
-            .and_then(|et| primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", et.tex_coord)))
-            .map(|n| {
-                self.accessor_to_contiguous_array(*n, |buf| {
-                    let s: &[u8; 8] = buf[0..8].try_into().unwrap();
-                    let res: [f32; 2] = bytemuck::cast(*s);
-                    res
-                })
-            });
+            .and_then(|et| self.read_transformed_tex_coords(primitive, et.tex_coord, &et.extensions));
 
         let base_color_tex_coords = maybe_material
             .and_then(|mat| mat.pbr_metallic_roughness.as_ref())
             .and_then(|pmr| pmr.base_color_texture.as_ref())
-            .and_then(|bct| primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", bct.tex_coord)))
-            .map(|n| {
-                self.accessor_to_contiguous_array(*n, |buf| {
-                    let s: &[u8; 8] = buf[0..8].try_into().unwrap();
-                    let res: [f32; 2] = bytemuck::cast(*s);
-                    res
-                })
-            });
+            .and_then(|bct| self.read_transformed_tex_coords(primitive, bct.tex_coord, &bct.extensions));
 
         let metallic_roughness_tex_coords = maybe_material
             .and_then(|mat| mat.pbr_metallic_roughness.as_ref())
             .and_then(|pmr| pmr.metallic_roughness_texture.as_ref())
-            .and_then(|mrt| primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", mrt.tex_coord)))
-            .map(|n| {
-                self.accessor_to_contiguous_array(*n, |buf| {
-                    let s: &[u8; 8] = buf[0..8].try_into().unwrap();
-                    let res: [f32; 2] = bytemuck::cast(*s);
-                    res
-                })
-            });
+            .and_then(|mrt| self.read_transformed_tex_coords(primitive, mrt.tex_coord, &mrt.extensions));
 
         let mut vertices = vec![];
         for i in 0..positions.len() {
@@ -690,7 +950,7 @@ impl GLTF {
         let image_format = match image.mime_type {
             Some(MimeType::PNG) => { image::ImageFormat::Png },
             Some(MimeType::JPEG) => { image::ImageFormat::Jpeg },
-            _ => panic!("Unknown image format")
+            None => unreachable!("image mime types are checked in GLTF::new"),
         };
         let bv = &self.scene.buffer_views[image.buffer_view.unwrap()];
         let start_offset = bv.byte_offset.unwrap_or(0u32) as usize;
@@ -708,6 +968,8 @@ impl GLTF {
             address_mode_v: sampler.wrap_t.as_ref().unwrap_or(&SamplerWrapMode::Repeat).to_wgpu_address_mode(),
             mag_filter: sampler.mag_filter.as_ref().unwrap_or(&SamplerMagFilterType::Nearest).to_wgpu_filter_mode(),
             min_filter: sampler.min_filter.as_ref().unwrap_or(&SamplerMinFilterType::Nearest).to_wgpu_filter_mode(),
+            // no min filter leaves it to the implementation, trilinear looks best
+            mipmap_filter: sampler.min_filter.as_ref().map_or(Some(wgpu::FilterMode::Linear), SamplerMinFilterType::to_wgpu_mipmap_filter_mode),
         }
     }
 
@@ -740,6 +1002,19 @@ impl GLTF {
                 pbr_material.emissive_factor = factor.map(|f| f as f32);
             }
 
+            // extras can be any json, anything that isn't a number is ignored
+            let extra = |name: &str| material.extras.as_ref()
+                .and_then(|extras| extras.get(name))
+                .and_then(serde_json::Value::as_f64)
+                .map(|v| v as f32);
+            pbr_material.render_queue = match material.alpha_mode {
+                Some(AlphaMode::Mask) => pbr::Material::RENDER_QUEUE_MASKED,
+                Some(AlphaMode::Blend) => pbr::Material::RENDER_QUEUE_TRANSPARENT,
+                Some(AlphaMode::Opaque) | None => pbr::Material::RENDER_QUEUE_OPAQUE,
+            };
+            pbr_material.shadow_depth_bias = extra("shadow_depth_bias");
+            pbr_material.shadow_normal_bias = extra("shadow_normal_bias");
+
             if let Some(texture_and_sampler) = material.pbr_metallic_roughness.as_ref()
                 .and_then(|pmr| pmr.base_color_texture.as_ref())
                 .map(|t| self.load_texture(t.index))
@@ -782,31 +1057,143 @@ impl GLTF {
         pbr_material
     }
 
+    fn read_tex_coords(&self, primitive: &Primitive, tex_coord: usize) -> Option<Vec<[f32; 2]>> {
+        primitive.attributes.additional_fields.get(&format!("TEXCOORD_{}", tex_coord)).map(|n| {
+            self.accessor_to_contiguous_array(*n, |buf| {
+                let s: &[u8; 8] = buf[0..8].try_into().unwrap();
+                let res: [f32; 2] = bytemuck::cast(*s);
+                res
+            })
+        })
+    }
+
+    fn read_transformed_tex_coords(
+        &self, primitive: &Primitive, tex_coord: usize, extensions: &Option<TextureInfoExtensions>,
+    ) -> Option<Vec<[f32; 2]>> {
+        let (tex_coord, transform) = uv_set(tex_coord, extensions);
+        let uvs = self.read_tex_coords(primitive, tex_coord)?;
+        Some(match transform {
+            Some(t) => uvs.into_iter().map(|uv| t.apply(uv)).collect(),
+            None => uvs,
+        })
+    }
+
+    fn texture_clamps_to_edge(&self, texture_idx: usize) -> (bool, bool) {
+        let sampler = self.scene.textures.as_ref().unwrap()[texture_idx].sampler
+            .and_then(|i| self.scene.samplers.as_ref().map(|samplers| &samplers[i]));
+        let is_clamp = |mode: Option<&SamplerWrapMode>| matches!(mode, Some(SamplerWrapMode::ClampToEdge));
+        match sampler {
+            Some(s) => (is_clamp(s.wrap_s.as_ref()), is_clamp(s.wrap_t.as_ref())),
+            None => (false, false),
+        }
+    }
+
+    fn report_uv_issues(
+        &self,
+        mesh_idx: usize,
+        primitive_idx: usize,
+        primitive: &Primitive,
+        vertices: &[pbr::Vertex],
+        indices: &pbr::VertexIndices,
+        pbr_material: &pbr::Material,
+    ) {
+        let material = match (primitive.material, &self.scene.materials) {
+            (Some(i), Some(mats)) => &mats[i],
+            _ => return,
+        };
+        let pmr = material.pbr_metallic_roughness.as_ref();
+        let slots = [
+            ("base color", pmr.and_then(|p| p.base_color_texture.as_ref()).map(|t| (t.index, t.tex_coord, &t.extensions)), &pbr_material.base_color_texture.0),
+            ("metallic roughness", pmr.and_then(|p| p.metallic_roughness_texture.as_ref()).map(|t| (t.index, t.tex_coord, &t.extensions)), &pbr_material.metallic_roughness_texture.0),
+            ("normal", material.normal_texture.as_ref().map(|t| (t.index, t.tex_coord, &t.extensions)), &pbr_material.normal_texture.0),
+            ("occlusion", material.occlusion_texture.as_ref().map(|t| (t.index, t.tex_coord, &t.extensions)), &pbr_material.occlusion_texture.0),
+            ("emissive", material.emissive_texture.as_ref().map(|t| (t.index, t.tex_coord, &t.extensions)), &pbr_material.emissive_texture.0),
+        ];
+        let indices: Vec<u32> = match indices {
+            pbr::VertexIndices::U16(v) => v.iter().map(|i| *i as u32).collect(),
+            pbr::VertexIndices::U32(v) => v.clone(),
+        };
+        let positions: Vec<[f32; 3]> = vertices.iter().map(|v| v.position).collect();
+        let limits = uv_analysis::limits();
+
+        for (slot, texture, image) in slots {
+            if let Some((texture_idx, tex_coord, extensions)) = texture {
+                let uvs = match self.read_transformed_tex_coords(primitive, tex_coord, extensions) {
+                    Some(uvs) => uvs,
+                    None => continue,
+                };
+                let prefix = format!("GLTF: mesh {} primitive {} {} texture (TEXCOORD_{})", mesh_idx, primitive_idx, slot, uv_set(tex_coord, extensions).0);
+
+                let invalid = uv_analysis::invalid_triangles(&indices, uvs.len().min(positions.len()));
+                if invalid > 0 {
+                    log::warn!("{}: {} triangles index past the {} uvs / {} positions, skipped by the uv checks", prefix, invalid, uvs.len(), positions.len());
+                }
+
+                let bounds = uv_analysis::UvBounds::from_uvs(&uvs);
+                let (clamp_u, clamp_v) = self.texture_clamps_to_edge(texture_idx);
+                if (clamp_u && bounds.exceeds_unit_square(0)) || (clamp_v && bounds.exceeds_unit_square(1)) {
+                    log::warn!(
+                        "{}: uvs span {:?}..{:?} but the sampler clamps to edge, texture will smear",
+                        prefix, bounds.min, bounds.max
+                    );
+                }
+
+                // the occlusion texture is the closest thing gltf has to a lightmap, its uvs must not overlap
+                if slot == "occlusion" {
+                    let overlap = uv_analysis::overlap_ratio(&uvs, &indices);
+                    if overlap > limits.max_overlap_ratio {
+                        log::warn!("{}: {:.1}% of the uv area is overlapping", prefix, overlap * 100.0);
+                    }
+                }
+
+                if let Some(density) = uv_analysis::texel_density(&positions, &uvs, &indices, (image.width(), image.height())) {
+                    log::debug!(
+                        "{}: texel density (texels per unit) min {:.1} avg {:.1} max {:.1}",
+                        prefix, density.min, density.avg, density.max
+                    );
+                    if density.min < limits.min_texel_density || density.max > limits.max_texel_density {
+                        log::warn!(
+                            "{}: texel density {:.1}..{:.1} is outside of {:.1}..{:.1}",
+                            prefix, density.min, density.max, limits.min_texel_density, limits.max_texel_density
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // KHR_lights_punctual point and spot lights of the rendered scene
+    pub fn to_punctual_lights(&self) -> Vec<PunctualLight> {
+        let lights = match self.scene.extensions.as_ref().and_then(|e| e.khr_lights_punctual.as_ref()) {
+            Some(lights_punctual) => &lights_punctual.lights,
+            None => return vec![],
+        };
+        let mut acc = vec![];
+        for node_idx in root_nodes(&self.scene) {
+            collect_punctual_lights(&self.scene, lights, node_idx, Matrix4::identity(), &mut acc);
+        }
+        acc
+    }
+
     pub fn to_pbr_meshes(&self) -> Vec<pbr::Mesh> {
         let mut mesh_instances = scene_to_mesh_instances(&self.scene);
         let mut pbr_meshes = vec![];
         for mesh_idx in 0..self.scene.meshes.len() {
             let mesh = &self.scene.meshes[mesh_idx];
+            // meshes that no node in the scene references aren't drawn
+            let (instances, instance_names) = match mesh_instances.remove(&mesh_idx) {
+                Some(instances) => instances.into_iter().unzip(),
+                None => continue,
+            };
             let mut pbr_primitives = vec![];
             for primitive_idx in 0..mesh.primitives.len() {
                 let primitive = &mesh.primitives[primitive_idx];
-                
-                let has_vertex_normals = primitive.attributes.normal.is_some();
-                let has_normal_map = primitive.material.as_ref()
-                    .and_then(|mat_idx| self.scene.materials.as_ref().map(|mats| &mats[*mat_idx]))
-                    .and_then(|mat| mat.normal_texture.as_ref())
-                    .is_some();
-                let has_tangents = primitive.attributes.tangent.is_some();
-                if !has_vertex_normals {
-                    panic!("No vertex normals! Have to implement generation.");
-                }
-                if has_normal_map && !has_tangents {
-                    panic!("Primitive has a normal map, but no tangents. Tangent generation needs to be implemented.");
-                }
+                // normals and tangents are checked in GLTF::new
 
                 let vertices = self.primitive_to_pbr_vertices(primitive);
                 let indices = self.accessor_to_pbr_indices(primitive.indices);
                 let material = self.material_to_pbr(primitive.material);
+                self.report_uv_issues(mesh_idx, primitive_idx, primitive, &vertices, &indices, &material);
                 pbr_primitives.push(pbr::Primitive {
                     vertices,
                     indices,
@@ -814,8 +1201,10 @@ impl GLTF {
                 });
             }
             pbr_meshes.push(pbr::Mesh {
+                gltf_mesh_idx: mesh_idx,
                 primitives: pbr_primitives,
-                instances: mesh_instances.remove(&mesh_idx).unwrap(),
+                instances,
+                instance_names,
             });
         }
 
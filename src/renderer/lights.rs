@@ -1,88 +1,333 @@
-use cgmath::{InnerSpace, Vector3};
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Transform, Vector3};
 use wgpu::util::DeviceExt;
 
+use super::{bind_group_layouts, bounds::Aabb, pipelines::shadow::ShadowMap, utils::env_f32, wgpu_context::OPENGL_TO_WGPU_MATRIX};
+
+/*
+* The punctual lights live in a storage buffer that grows with the scene. The gl safe mode has no
+* storage buffers, there pbr.wgsl is rewritten to read a fixed size uniform array of this many
+* lights and the rest are dropped.
+*/
+pub const MAX_PUNCTUAL_LIGHTS: usize = 64;
+
+pub fn punctual_lights_in_storage(device: &wgpu::Device) -> bool {
+    device.limits().max_storage_buffers_per_shader_stage > 0
+}
+
+// pbr.wgsl for devices without storage buffers, see MAX_PUNCTUAL_LIGHTS
+pub fn uniform_punctual_lights_shader(source: String) -> String {
+    const STORAGE_ARRAY: &str = "lights: array<PunctualLight>,";
+    const STORAGE_BINDING: &str = "var<storage, read> punctual_lights";
+    if !source.contains(STORAGE_ARRAY) || !source.contains(STORAGE_BINDING) {
+        log::error!("pbr.wgsl: punctual lights declaration not found, can't fall back to a uniform array");
+        return source;
+    }
+    source
+        .replace(STORAGE_ARRAY, &format!("lights: array<PunctualLight, {}>,", MAX_PUNCTUAL_LIGHTS))
+        .replace(STORAGE_BINDING, "var<uniform> punctual_lights")
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PunctualLightKind {
+    Point,
+    // cone angles from the spot direction, full intensity inside the inner one, none outside the outer one
+    Spot { inner_cone: cgmath::Rad<f32>, outer_cone: cgmath::Rad<f32> },
+}
+
+// point and spot lights, as in KHR_lights_punctual
+#[derive(Copy, Clone, Debug)]
+pub struct PunctualLight {
+    pub kind: PunctualLightKind,
+    pub position: Point3<f32>,
+    // where a spot light points, unused for point lights
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    // luminous intensity in candela
+    pub intensity: f32,
+    // distance where the light fades out completely, None for inverse square falloff only
+    pub range: Option<f32>,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PunctualLightUniform {
+    position: [f32; 3],
+    // 0 for no range
+    range: f32,
+    direction: [f32; 3],
+    // cone attenuation is clamp(cos_angle * spot_scale + spot_offset), scale 0 and offset 1 for point lights
+    spot_scale: f32,
+    color: [f32; 3],
+    spot_offset: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SunUniform {
+    direction: [f32; 3],
+    shadow_depth_bias: f32,
+    // color * illuminance
+    color: [f32; 3],
+    shadow_normal_bias: f32,
+}
+
+// the lights follow at the array's 16 byte alignment
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PunctualLightsHeader {
+    count: u32,
+    _padding: [u32; 3],
+}
+
+impl PunctualLight {
+    fn to_uniform(self) -> PunctualLightUniform {
+        let (spot_scale, spot_offset) = match self.kind {
+            PunctualLightKind::Point => (0.0, 1.0),
+            PunctualLightKind::Spot { inner_cone, outer_cone } => {
+                let cos_outer = outer_cone.0.cos();
+                let scale = 1.0 / (inner_cone.0.cos() - cos_outer).max(0.001);
+                (scale, -cos_outer * scale)
+            },
+        };
+        // point lights ignore the direction, a zero one must not turn into nans
+        let direction = match self.kind {
+            PunctualLightKind::Spot { .. } if self.direction.magnitude2() > 0.0 => self.direction.normalize(),
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        };
+        PunctualLightUniform {
+            position: self.position.into(),
+            range: self.range.unwrap_or(0.0),
+            direction: direction.into(),
+            spot_scale,
+            color: self.color.map(|c| c * self.intensity),
+            spot_offset,
+        }
+    }
+}
+
 pub struct Lights {
+    pub punctual_lights: Vec<PunctualLight>,
     direction: [f32; 3],
     color: [f32; 3],
+    // the sun is uploaded as color * illuminance
+    illuminance_lux: f32,
+    // WGPU_TEST_SHADOW_DEPTH_BIAS / WGPU_TEST_SHADOW_NORMAL_BIAS, materials can override them
+    shadow_depth_bias: f32,
+    shadow_normal_bias: f32,
 }
 
 pub struct LightsBinding {
     pub bind_group: wgpu::BindGroup,
-    direction_buffer: wgpu::Buffer,
-    color_buffer: wgpu::Buffer,
+    buffers: LightsBuffers,
+    // lights that fit in the punctual lights buffer, fixed in uniform mode
+    punctual_light_capacity: usize,
+    punctual_lights_in_storage: bool,
+}
+
+struct LightsBuffers {
+    sun: wgpu::Buffer,
+    punctual_lights: wgpu::Buffer,
+    // shared with the shadow pipeline
+    light_view_proj: wgpu::Buffer,
 }
 
 impl Default for Lights {
     fn default() -> Self {
         Lights {
+            punctual_lights: vec![],
             direction: Vector3::new(1.0, -1.0, 1.0).normalize().into(),
-            color: [10.0, 10.0, 10.0],
+            color: [1.0, 1.0, 1.0],
+            illuminance_lux: 10.0,
+            shadow_depth_bias: env_f32("WGPU_TEST_SHADOW_DEPTH_BIAS").unwrap_or(0.0),
+            shadow_normal_bias: env_f32("WGPU_TEST_SHADOW_NORMAL_BIAS").unwrap_or(0.5),
         }
     }
 }
 
 impl Lights {
-    pub fn upload(&self, device: &wgpu::Device, bind_group_layout: &wgpu::BindGroupLayout) -> LightsBinding {
-        let direction_buffer = device.create_buffer_init(
+    fn sun_uniform(&self) -> SunUniform {
+        SunUniform {
+            direction: self.direction,
+            shadow_depth_bias: self.shadow_depth_bias,
+            color: self.color.map(|c| c * self.illuminance_lux),
+            shadow_normal_bias: self.shadow_normal_bias,
+        }
+    }
+
+    // the global shadow biases, depth bias in shadow map depth units, normal bias in texels
+    pub fn set_shadow_bias(&mut self, depth_bias: f32, normal_bias: f32) {
+        self.shadow_depth_bias = depth_bias;
+        self.shadow_normal_bias = normal_bias;
+    }
+
+    /*
+    * Orthographic sun projection covering the part of the scene the camera sees. The depth range
+    * spans the whole scene, so casters behind the camera still shadow what's in view.
+    */
+    pub fn sun_view_proj(&self, camera_view_proj: &Matrix4<f32>, scene_bounds: Option<&Aabb>) -> Matrix4<f32> {
+        let direction = Vector3::from(self.direction);
+        let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+        let light_view = Matrix4::look_at_rh(Point3::origin(), Point3::from_vec(direction), up);
+
+        let to_light = camera_view_proj.invert().map(|inv| light_view * inv).unwrap_or(light_view);
+        let ndc_corners = Aabb { min: Point3::new(-1.0, -1.0, 0.0), max: Point3::new(1.0, 1.0, 1.0) }.corners();
+        let view_bounds = Aabb::from_points(ndc_corners.map(|c| to_light.transform_point(c))).unwrap();
+        let bounds = match scene_bounds {
+            Some(scene) => {
+                let scene = scene.transform(&light_view);
+                let min = Point3::new(view_bounds.min.x.max(scene.min.x), view_bounds.min.y.max(scene.min.y), scene.min.z);
+                let max = Point3::new(view_bounds.max.x.min(scene.max.x), view_bounds.max.y.min(scene.max.y), scene.max.z);
+                // the camera looks away from the scene
+                if min.x >= max.x || min.y >= max.y { scene } else { Aabb { min, max } }
+            },
+            None => view_bounds,
+        };
+
+        // light space looks down -z, keep a bit of room so casters on the bounds aren't clipped
+        let margin = (bounds.max.z - bounds.min.z) * 0.01 + 0.01;
+        let proj = cgmath::ortho(
+            bounds.min.x, bounds.max.x, bounds.min.y, bounds.max.y,
+            -bounds.max.z - margin, -bounds.min.z + margin,
+        );
+        OPENGL_TO_WGPU_MATRIX * proj * light_view
+    }
+
+    /*
+    * The contents of the punctual lights buffer, padded to capacity lights. Lights past the capacity
+    * are dropped, which only happens in uniform mode since the storage buffer grows to fit.
+    */
+    fn punctual_lights_contents(&self, capacity: usize) -> Vec<u8> {
+        if self.punctual_lights.len() > capacity {
+            log::warn!("{} punctual lights, only the first {} are drawn", self.punctual_lights.len(), capacity);
+        }
+        let mut uniforms: Vec<PunctualLightUniform> = self.punctual_lights.iter()
+            .take(capacity)
+            .map(|light| light.to_uniform())
+            .collect();
+        let header = PunctualLightsHeader { count: uniforms.len() as u32, _padding: [0; 3] };
+        uniforms.resize(capacity, bytemuck::Zeroable::zeroed());
+        let mut contents = bytemuck::bytes_of(&header).to_vec();
+        contents.extend_from_slice(bytemuck::cast_slice(&uniforms));
+        contents
+    }
+
+    // room for at least one light, a runtime sized array can't bind an empty buffer
+    fn punctual_light_capacity(&self, in_storage: bool) -> usize {
+        if in_storage { self.punctual_lights.len().max(1).next_power_of_two() } else { MAX_PUNCTUAL_LIGHTS }
+    }
+
+    fn create_punctual_lights_buffer(&self, device: &wgpu::Device, in_storage: bool, capacity: usize) -> wgpu::Buffer {
+        let usage = if in_storage { wgpu::BufferUsages::STORAGE } else { wgpu::BufferUsages::UNIFORM };
+        device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Punctual Lights Buffer"),
+                contents: &self.punctual_lights_contents(capacity),
+                usage: usage | wgpu::BufferUsages::COPY_DST,
+            }
+        )
+    }
+
+    pub fn upload(&self, device: &wgpu::Device, bind_group_layout: &wgpu::BindGroupLayout, shadow_map: &ShadowMap) -> LightsBinding {
+        let sun_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
-                label: Some("Lights Direction Buffer"),
-                contents: bytemuck::cast_slice(&self.direction),
+                label: Some("Sun Buffer"),
+                contents: bytemuck::cast_slice(&[self.sun_uniform()]),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
-
-        let color_buffer = device.create_buffer_init(
+        let punctual_lights_in_storage = punctual_lights_in_storage(device);
+        let punctual_light_capacity = self.punctual_light_capacity(punctual_lights_in_storage);
+        let punctual_lights_buffer = self.create_punctual_lights_buffer(device, punctual_lights_in_storage, punctual_light_capacity);
+        // updated with the camera, see sun_view_proj
+        let identity: [[f32; 4]; 4] = Matrix4::identity().into();
+        let light_view_proj_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
-                label: Some("Lights Color Buffer"),
-                contents: bytemuck::cast_slice(&self.color),
+                label: Some("Light View Projection Buffer"),
+                contents: bytemuck::cast_slice(&identity),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let buffers = LightsBuffers {
+            sun: sun_buffer,
+            punctual_lights: punctual_lights_buffer,
+            light_view_proj: light_view_proj_buffer,
+        };
+        LightsBinding {
+            bind_group: buffers.bind_group(device, bind_group_layout, shadow_map),
+            buffers, punctual_light_capacity, punctual_lights_in_storage,
+        }
+    }
+
+    // the generated layout, with the punctual lights as a uniform instead without storage buffers
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let mut entries = bind_group_layouts::lights::ENTRIES.to_vec();
+        if !punctual_lights_in_storage(device) {
+            for entry in entries.iter_mut().filter(|e| e.binding == bind_group_layouts::lights::PUNCTUAL_LIGHTS) {
+                entry.ty = wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None };
+            }
+        }
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("Lights Bind Group Layout"),
+        })
+    }
+}
+
+impl LightsBuffers {
+    fn bind_group(&self, device: &wgpu::Device, bind_group_layout: &wgpu::BindGroupLayout, shadow_map: &ShadowMap) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: direction_buffer.as_entire_binding(),
+                    binding: bind_group_layouts::lights::SUN,
+                    resource: self.sun.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: color_buffer.as_entire_binding(),
+                    binding: bind_group_layouts::lights::LIGHT_VIEW_PROJ,
+                    resource: self.light_view_proj.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: bind_group_layouts::lights::SHADOW_MAP,
+                    resource: wgpu::BindingResource::TextureView(&shadow_map.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: bind_group_layouts::lights::SHADOW_SAMPLER,
+                    resource: wgpu::BindingResource::Sampler(&shadow_map.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: bind_group_layouts::lights::PUNCTUAL_LIGHTS,
+                    resource: self.punctual_lights.as_entire_binding(),
                 },
             ],
             label: Some("Lights Bind Group"),
-        });
-
-        LightsBinding { bind_group, direction_buffer, color_buffer }
+        })
     }
+}
 
-    pub fn desc() -> wgpu::BindGroupLayoutDescriptor<'static> {
-        wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-            label: Some("Lights Bind Group Layout"),
+
+impl LightsBinding {
+    /*
+    * Rewrites the sun, including the global shadow biases, and the punctual lights. The storage
+    * buffer is reallocated (and the bind group rebuilt) when the lights outgrow it.
+    */
+    pub fn update(&mut self, lights: &Lights, device: &wgpu::Device, queue: &wgpu::Queue, bind_group_layout: &wgpu::BindGroupLayout, shadow_map: &ShadowMap) {
+        queue.write_buffer(&self.buffers.sun, 0, bytemuck::cast_slice(&[lights.sun_uniform()]));
+        if self.punctual_lights_in_storage && lights.punctual_lights.len() > self.punctual_light_capacity {
+            self.punctual_light_capacity = lights.punctual_light_capacity(true);
+            self.buffers.punctual_lights = lights.create_punctual_lights_buffer(device, true, self.punctual_light_capacity);
+            self.bind_group = self.buffers.bind_group(device, bind_group_layout, shadow_map);
+            return;
         }
+        queue.write_buffer(&self.buffers.punctual_lights, 0, &lights.punctual_lights_contents(self.punctual_light_capacity));
     }
-}
 
+    pub fn light_view_proj_buffer(&self) -> &wgpu::Buffer {
+        &self.buffers.light_view_proj
+    }
+
+    pub fn update_light_view_proj(&self, light_view_proj: &Matrix4<f32>, queue: &wgpu::Queue) {
+        let m: [[f32; 4]; 4] = (*light_view_proj).into();
+        queue.write_buffer(&self.buffers.light_view_proj, 0, bytemuck::cast_slice(&m));
+    }
+}
@@ -1,11 +1,17 @@
 mod utils;
 mod texture;
-mod camera;
-mod lights;
+mod bind_group_layouts;
+mod bounds;
+pub mod color_lut;
+pub mod camera;
+mod frame_globals;
+mod shader_source;
+pub mod lights;
 mod wgpu_context;
 pub mod gltf;
 mod pipelines;
 mod depth_texture;
 mod msaa_textures;
+mod uv_analysis;
 pub mod renderer;
 
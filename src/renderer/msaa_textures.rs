@@ -1,7 +1,11 @@
 pub struct MSAATextures {
+    // the textures are kept alive for the views
+    #[allow(dead_code)]
     msaa_texture: wgpu::Texture,
     pub msaa_texture_view: wgpu::TextureView,
+    #[allow(dead_code)]
     pub msaa_sampler: wgpu::Sampler,
+    #[allow(dead_code)]
     resolve_texture: wgpu::Texture,
     pub resolve_texture_view: wgpu::TextureView,
     pub resolve_sampler: wgpu::Sampler,
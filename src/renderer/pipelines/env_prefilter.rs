@@ -10,6 +10,20 @@ const INDICES: &[u16] = &[
     3, 2, 0,
 ];
 
+const MAX_MIPMAP_COUNT: u32 = 6;
+// below this the face borders make up most of the texels, and bilinear filtering across
+// the (separately rendered) faces shows up as seams on rough surfaces
+const MIN_FACE_RESOLUTION: u32 = 4;
+
+/*
+* Number of roughness levels baked, the last mip is roughness 1.0.
+* The pbr shader derives its max reflection lod from the texture's level count, so the two always agree.
+*/
+fn mipmap_count(cubemap_face_resolution: u32) -> u32 {
+    let levels_above_min = cubemap_face_resolution.max(MIN_FACE_RESOLUTION).ilog2() - MIN_FACE_RESOLUTION.ilog2();
+    (levels_above_min + 1).min(MAX_MIPMAP_COUNT)
+}
+
 pub struct Roughness {
     roughness: f32,
 }
@@ -127,7 +141,7 @@ impl EnvPrefilterPipeline {
         face_rot_bind_group_layout: &wgpu::BindGroupLayout,
         cubemap_face_resolution: u32,
     ) -> Result<wgpu::Texture, wgpu::SurfaceError> {
-        let mipmap_count = 6;
+        let mipmap_count = mipmap_count(cubemap_face_resolution);
 
         let index_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
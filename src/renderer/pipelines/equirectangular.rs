@@ -42,7 +42,7 @@ impl EquirectangularHdrEnvironmentMap {
     }
 
     fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue, bind_group_layout: &wgpu::BindGroupLayout) -> EquirectangularHdrEnvironmentMapBinding {
-        let texture = Texture::from_image(device, queue, &self.map, false);
+        let texture = Texture::from_image(device, queue, &self.map, false, false);
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             min_filter: wgpu::FilterMode::Nearest,
             mag_filter: wgpu::FilterMode::Nearest,
@@ -191,6 +191,7 @@ pub fn render_cubemap(
     let equirectangular_environment_map = EquirectangularHdrEnvironmentMap { map: (image, Some(pbr::SamplerOptions {
         mag_filter: wgpu::FilterMode::Nearest,
         min_filter: wgpu::FilterMode::Nearest,
+        mipmap_filter: None,
         address_mode_u: wgpu::AddressMode::ClampToEdge,
         address_mode_v: wgpu::AddressMode::ClampToEdge,
     })) };
@@ -300,6 +301,7 @@ pub fn render_cubemap(
 }
 
 // for testing:
+#[allow(dead_code)]
 pub fn write_texture_to_file(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture, face_index: u32, mip_level: u32) {
     let cubemap_face_resolution = texture.width();
     let mip_resolution = (cubemap_face_resolution >> mip_level).max(1);
@@ -6,3 +6,4 @@ pub mod mipmap;
 pub mod pbr;
 pub mod post_processing;
 
+pub mod shadow;
@@ -1,9 +1,9 @@
-use std::{fs::File, io::Read, mem::size_of};
+use std::{collections::HashMap, hash::{Hash, Hasher}, mem::size_of, ops::Range, sync::Arc};
 
 use cgmath::{Matrix, Matrix3, Matrix4, SquareMatrix, Transform};
 use wgpu::util::DeviceExt;
 
-use crate::renderer::{msaa_textures::MSAATextures, renderer::WorldBinding, texture::Texture};
+use crate::renderer::{bind_group_layouts, bounds::{Aabb, Frustum}, lights, msaa_textures::MSAATextures, renderer::WorldBinding, texture::Texture};
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -23,6 +23,11 @@ impl Default for Instance {
 
 impl Instance {
     const BASE_SHADER_LOCATION: u32 = 0;
+
+    pub fn transform(&self) -> Matrix4<f32> {
+        Matrix4::from(self.m4)
+    }
+
     const ATTRIBUTES: [wgpu::VertexAttribute; 7] = [
         wgpu::VertexAttribute {
             offset: 0,
@@ -206,13 +211,22 @@ pub struct Material {
     pub base_color_texture: (image::DynamicImage, Option<SamplerOptions>),
     pub metallic_roughness_texture: (image::DynamicImage, Option<SamplerOptions>),
     pub normal_texture_scale: f32,
+    // overrides of the global shadow biases (see Lights) for content that needs its own, e.g.
+    // thin parts that show acne; depth bias in shadow map depth units, normal bias in texels
+    pub shadow_depth_bias: Option<f32>,
+    pub shadow_normal_bias: Option<f32>,
+    // draw order, lower queues are drawn first, see the RENDER_QUEUE_* ranges
+    pub render_queue: i32,
 }
 
+#[derive(Hash, PartialEq)]
 pub struct SamplerOptions {
     pub address_mode_u: wgpu::AddressMode,
     pub address_mode_v: wgpu::AddressMode,
     pub mag_filter: wgpu::FilterMode,
     pub min_filter: wgpu::FilterMode,
+    // None samples only the base level
+    pub mipmap_filter: Option<wgpu::FilterMode>,
 }
 
 impl SamplerOptions {
@@ -222,6 +236,8 @@ impl SamplerOptions {
             address_mode_v: self.address_mode_v,
             mag_filter: self.mag_filter,
             min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter.unwrap_or(wgpu::FilterMode::Nearest),
+            lod_max_clamp: if self.mipmap_filter.is_some() { 32.0 } else { 0.0 },
             ..wgpu::SamplerDescriptor::default()
         }
     }
@@ -252,178 +268,163 @@ impl Default for Material {
             base_color_texture: (default_texture.clone(), None),
             metallic_roughness_texture: (default_texture, None),
             normal_texture_scale: 1.0,
+            shadow_depth_bias: None,
+            shadow_normal_bias: None,
+            render_queue: Material::RENDER_QUEUE_OPAQUE,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum TextureSlot {
+    Normal,
+    Occlusion,
+    Emissive,
+    BaseColor,
+    MetallicRoughness,
+}
+
+/*
+* Uploads each distinct material once. Exporters often emit one material per mesh even when they're
+* identical, sharing the binding saves the duplicate textures and bind groups.
+* Materials are looked up by content hash and compared in full on a hit, a collision uploads both.
+* Both the hash and the comparison read every texel of every texture, so dedup costs roughly two passes
+* over the texture data at load time (a lot less than the upload it can save, but not free for big scenes).
+* Set WGPU_TEST_NO_MATERIAL_DEDUP=1 to upload every primitive's material separately.
+* Sharing is counted by the Arc, the cache has to be dropped after the upload (see Renderer::unshare_material).
+*/
+pub struct MaterialCache<'a> {
+    bindings: HashMap<u64, Vec<(&'a Material, Arc<MaterialBinding>)>>,
+    dedup: bool,
+    pub requested: u32,
+    pub uploaded: u32,
+}
+
+impl<'a> MaterialCache<'a> {
+    pub fn new() -> Self {
+        let dedup = !std::env::var("WGPU_TEST_NO_MATERIAL_DEDUP").is_ok_and(|v| v == "1");
+        Self { bindings: HashMap::new(), dedup, requested: 0, uploaded: 0 }
+    }
+
+    fn get_or_upload(
+        &mut self, material: &'a Material, device: &wgpu::Device, queue: &wgpu::Queue,
+        material_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Arc<MaterialBinding> {
+        self.requested += 1;
+        if !self.dedup {
+            self.uploaded += 1;
+            return Arc::new(material.upload(device, queue, material_bind_group_layout));
         }
+        let candidates = self.bindings.entry(material.content_hash()).or_default();
+        if let Some((_, binding)) = candidates.iter().find(|(other, _)| other.same_content(material)) {
+            return binding.clone();
+        }
+        self.uploaded += 1;
+        let binding = Arc::new(material.upload(device, queue, material_bind_group_layout));
+        candidates.push((material, binding.clone()));
+        binding
     }
 }
 
 pub struct MaterialBinding {
     pub bind_group: wgpu::BindGroup,
+    // the factor buffers are only kept alive for the bind group
+    #[allow(dead_code)]
     base_color_factor: wgpu::Buffer,
+    #[allow(dead_code)]
     metallic_factor: wgpu::Buffer,
+    #[allow(dead_code)]
     roughness_factor: wgpu::Buffer,
+    #[allow(dead_code)]
     emissive_factor: wgpu::Buffer,
     normal_texture: Texture,
     occlusion_texture: Texture,
     emissive_texture: Texture,
     base_color_texture: Texture,
     metallic_roughness_texture: Texture,
-    normal_texture_scale: wgpu::Buffer,
+    params: wgpu::Buffer,
+}
+
+// MaterialParams in pbr.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialParams {
+    // negative means the global bias
+    shadow_bias: [f32; 2],
+    normal_texture_scale: f32,
+    _padding: f32,
 }
 impl Material {
+    /*
+    * Reserved render queue ranges, each bucket owns the values from its constant up to the next one:
+    * background < 0 <= opaque < 1000 <= masked < 2000 <= transparent < 3000 <= overlay
+    * Within a queue draws keep the scene order. The queue only orders the draws: every queue shares
+    * the pbr pipeline's depth test and write, so overlay draws aren't on top of closer geometry
+    * until the depth hack in TODO.md lands.
+    */
+    pub const RENDER_QUEUE_BACKGROUND: i32 = -1000;
+    pub const RENDER_QUEUE_OPAQUE: i32 = 0;
+    pub const RENDER_QUEUE_MASKED: i32 = 1000;
+    pub const RENDER_QUEUE_TRANSPARENT: i32 = 2000;
+    pub const RENDER_QUEUE_OVERLAY: i32 = 3000;
+
     fn desc() -> wgpu::BindGroupLayoutDescriptor<'static> {
         wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                // base color factor
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                // metallic factor
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                // roughness factor
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                // emissive factor
-                wgpu::BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                // normal texture
-                wgpu::BindGroupLayoutEntry {
-                    binding: 4,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false
-                    },
-                    count: None,
-                },
-                // normal texture sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 5,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // occlusion texture
-                wgpu::BindGroupLayoutEntry {
-                    binding: 6,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false
-                    },
-                    count: None,
-                },
-                // occlusion texture sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 7,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // emissive texture
-                wgpu::BindGroupLayoutEntry {
-                    binding: 8,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false
-                    },
-                    count: None,
-                },
-                // emissive texture sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 9,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // base color texture
-                wgpu::BindGroupLayoutEntry {
-                    binding: 10,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false
-                    },
-                    count: None,
-                },
-                // base color texture sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 11,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // metallic roughness texture
-                wgpu::BindGroupLayoutEntry {
-                    binding: 12,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false
-                    },
-                    count: None,
-                },
-                // metallic roughness texture sampler
-                wgpu::BindGroupLayoutEntry {
-                    binding: 13,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                // normal texture scale
-                wgpu::BindGroupLayoutEntry {
-                    binding: 14,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
+            entries: bind_group_layouts::material::ENTRIES,
             label: Some("Material Bind Group Layout"),
         }
     }
 
-    fn upload(
+    // the uploaded factors as bits, so -0.0 / nan compare the way they hash
+    fn factor_bits(&self) -> Vec<u32> {
+        self.base_color_factor.iter().chain(&self.emissive_factor)
+            .chain(&[self.metallic_factor, self.roughness_factor, self.normal_texture_scale])
+            .chain(&shadow_bias_override(self.shadow_depth_bias, self.shadow_normal_bias))
+            .map(|f| f.to_bits())
+            .collect()
+    }
+
+    fn textures(&self) -> [&(image::DynamicImage, Option<SamplerOptions>); 5] {
+        [
+            &self.normal_texture, &self.occlusion_texture, &self.emissive_texture,
+            &self.base_color_texture, &self.metallic_roughness_texture,
+        ]
+    }
+
+    // hash of everything that ends up on the gpu, the texture contents rather than where they came from
+    fn content_hash(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.factor_bits().hash(&mut hasher);
+        self.render_queue.hash(&mut hasher);
+        for (img, sampler) in self.textures() {
+            image::GenericImageView::dimensions(img).hash(&mut hasher);
+            format!("{:?}", img.color()).hash(&mut hasher);
+            img.as_bytes().hash(&mut hasher);
+            sampler.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // the full comparison behind a content_hash match
+    fn same_content(&self, other: &Material) -> bool {
+        self.factor_bits() == other.factor_bits() && self.render_queue == other.render_queue
+            && self.textures().iter().zip(other.textures()).all(|((img, sampler), (other_img, other_sampler))| {
+                image::GenericImageView::dimensions(img) == image::GenericImageView::dimensions(other_img)
+                    && img.color() == other_img.color()
+                    && img.as_bytes() == other_img.as_bytes()
+                    && sampler == other_sampler
+            })
+    }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            shadow_bias: shadow_bias_override(self.shadow_depth_bias, self.shadow_normal_bias),
+            normal_texture_scale: self.normal_texture_scale,
+            _padding: 0.0,
+        }
+    }
+
+    pub fn upload(
         &self, device: &wgpu::Device, queue: &wgpu::Queue,
         material_bind_group_layout: &wgpu::BindGroupLayout,
     ) -> MaterialBinding {
@@ -455,80 +456,82 @@ impl Material {
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
-        let normal_texture_scale = device.create_buffer_init(
+        let params = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
-                label: Some("Normal Texture Scale Buffer"),
-                contents: bytemuck::cast_slice(&[self.normal_texture_scale]),
+                label: Some("Material Params Buffer"),
+                contents: bytemuck::cast_slice(&[self.params()]),
                 usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             }
         );
-        let normal_texture = Texture::from_image(device, queue, &self.normal_texture, false);
-        let occlusion_texture = Texture::from_image(device, queue, &self.occlusion_texture, false);
-        let emissive_texture = Texture::from_image(device, queue, &self.emissive_texture, true);
-        let base_color_texture = Texture::from_image(device, queue, &self.base_color_texture, true);
-        let metallic_roughness_texture = Texture::from_image(device, queue, &self.metallic_roughness_texture, false);
+        // the color space comes from the slot, not the image: gltf images that are shared between
+        // e.g. base color and metallic roughness get one srgb and one linear texture
+        let normal_texture = Texture::from_image(device, queue, &self.normal_texture, false, true);
+        let occlusion_texture = Texture::from_image(device, queue, &self.occlusion_texture, false, true);
+        let emissive_texture = Texture::from_image(device, queue, &self.emissive_texture, true, true);
+        let base_color_texture = Texture::from_image(device, queue, &self.base_color_texture, true, true);
+        let metallic_roughness_texture = Texture::from_image(device, queue, &self.metallic_roughness_texture, false, true);
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: material_bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
-                    binding: 0,
+                    binding: bind_group_layouts::material::BASE_COLOR_FACTOR,
                     resource: base_color_factor.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 1,
+                    binding: bind_group_layouts::material::METALLIC_FACTOR,
                     resource: metallic_factor.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 2,
+                    binding: bind_group_layouts::material::ROUGHNESS_FACTOR,
                     resource: roughness_factor.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 3,
+                    binding: bind_group_layouts::material::EMISSIVE_FACTOR,
                     resource: emissive_factor.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 4,
+                    binding: bind_group_layouts::material::NORMAL_TEXTURE,
                     resource: wgpu::BindingResource::TextureView(&normal_texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 5,
+                    binding: bind_group_layouts::material::NORMAL_TEXTURE_SAMPLER,
                     resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 6,
+                    binding: bind_group_layouts::material::OCCLUSION_TEXTURE,
                     resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 7,
+                    binding: bind_group_layouts::material::OCCLUSION_TEXTURE_SAMPLER,
                     resource: wgpu::BindingResource::Sampler(&occlusion_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 8,
+                    binding: bind_group_layouts::material::EMISSIVE_TEXTURE,
                     resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 9,
+                    binding: bind_group_layouts::material::EMISSIVE_TEXTURE_SAMPLER,
                     resource: wgpu::BindingResource::Sampler(&emissive_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 10,
+                    binding: bind_group_layouts::material::BASE_COLOR_TEXTURE,
                     resource: wgpu::BindingResource::TextureView(&base_color_texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 11,
+                    binding: bind_group_layouts::material::BASE_COLOR_TEXTURE_SAMPLER,
                     resource: wgpu::BindingResource::Sampler(&base_color_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 12,
+                    binding: bind_group_layouts::material::METALLIC_ROUGHNESS_TEXTURE,
                     resource: wgpu::BindingResource::TextureView(&metallic_roughness_texture.view),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 13,
+                    binding: bind_group_layouts::material::METALLIC_ROUGHNESS_TEXTURE_SAMPLER,
                     resource: wgpu::BindingResource::Sampler(&metallic_roughness_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 14,
-                    resource: normal_texture_scale.as_entire_binding(),
+                    binding: bind_group_layouts::material::MATERIAL_PARAMS,
+                    resource: params.as_entire_binding(),
                 },
             ],
             label: Some("Material Bind Group"),
@@ -544,9 +547,32 @@ impl Material {
             emissive_texture,
             base_color_texture,
             metallic_roughness_texture,
-            normal_texture_scale
+            params,
+        }
+    }
+}
+
+impl MaterialBinding {
+    pub fn texture(&self, slot: TextureSlot) -> &Texture {
+        match slot {
+            TextureSlot::Normal => &self.normal_texture,
+            TextureSlot::Occlusion => &self.occlusion_texture,
+            TextureSlot::Emissive => &self.emissive_texture,
+            TextureSlot::BaseColor => &self.base_color_texture,
+            TextureSlot::MetallicRoughness => &self.metallic_roughness_texture,
         }
     }
+
+    // None falls back to the global bias, only the bias part of the params is rewritten
+    pub fn set_shadow_bias(&self, queue: &wgpu::Queue, depth_bias: Option<f32>, normal_bias: Option<f32>) {
+        let bias = shadow_bias_override(depth_bias, normal_bias);
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&bias));
+    }
+}
+
+// what the shader gets, negative means the global bias
+fn shadow_bias_override(depth_bias: Option<f32>, normal_bias: Option<f32>) -> [f32; 2] {
+    [depth_bias.unwrap_or(-1.0), normal_bias.unwrap_or(-1.0)]
 }
 
 pub enum VertexIndices {
@@ -555,6 +581,38 @@ pub enum VertexIndices {
     U32(Vec<u32>),
 }
 
+impl VertexIndices {
+    // u16 when every index fits, exporters often write u32 indices for small meshes and narrowing halves the index buffer
+    pub fn narrowed(indices: Vec<u32>) -> Self {
+        if indices.iter().all(|i| *i <= u16::MAX as u32) {
+            VertexIndices::U16(indices.into_iter().map(|i| i as u16).collect())
+        } else {
+            VertexIndices::U32(indices)
+        }
+    }
+
+    pub fn format(&self) -> wgpu::IndexFormat {
+        match self {
+            VertexIndices::U16(_) => wgpu::IndexFormat::Uint16,
+            VertexIndices::U32(_) => wgpu::IndexFormat::Uint32,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            VertexIndices::U16(v) => bytemuck::cast_slice(v),
+            VertexIndices::U32(v) => bytemuck::cast_slice(v),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            VertexIndices::U16(v) => v.len(),
+            VertexIndices::U32(v) => v.len(),
+        }
+    }
+}
+
 pub struct Primitive {
     pub vertices: Vec<Vertex>,
     pub material: Material,
@@ -563,10 +621,14 @@ pub struct Primitive {
 
 pub struct PrimitiveBinding {
     pub vertex_buffer: wgpu::Buffer,
-    pub material_binding: MaterialBinding,
+    // shared by every primitive with an identical material
+    pub material_binding: Arc<MaterialBinding>,
     pub index_buffer: wgpu::Buffer,
+    // Uint16 whenever the indices fit (see VertexIndices::narrowed), set per draw in the pbr and shadow
+    // passes. Every primitive owns its index buffer, so primitives of one mesh can mix formats
     pub index_format: wgpu::IndexFormat,
     pub index_count: u32,
+    pub render_queue: i32,
 }
 
 impl Default for Primitive {
@@ -589,7 +651,10 @@ impl Default for Primitive {
 }
 
 impl Primitive {
-    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue, material_bind_group_layout: &wgpu::BindGroupLayout) -> PrimitiveBinding {
+    pub fn upload<'a>(
+        &'a self, device: &wgpu::Device, queue: &wgpu::Queue,
+        material_bind_group_layout: &wgpu::BindGroupLayout, materials: &mut MaterialCache<'a>,
+    ) -> PrimitiveBinding {
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
@@ -597,60 +662,172 @@ impl Primitive {
                 usage: wgpu::BufferUsages::VERTEX,
             }
         );
-        let material_binding = self.material.upload(device, queue, material_bind_group_layout);
-        let (indices, index_format, index_count) = match self.indices {
-            VertexIndices::U16(ref v) => {
-                (bytemuck::cast_slice(v), wgpu::IndexFormat::Uint16, v.len() as u32)
-            },
-            VertexIndices::U32(ref v) => {
-                (bytemuck::cast_slice(v), wgpu::IndexFormat::Uint32, v.len() as u32)
-            },
-        };
+        let material_binding = materials.get_or_upload(&self.material, device, queue, material_bind_group_layout);
         let index_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: indices,
+                contents: self.indices.as_bytes(),
                 usage: wgpu::BufferUsages::INDEX,
             }
         );
 
-        PrimitiveBinding { vertex_buffer, material_binding, index_buffer, index_format, index_count }
+        PrimitiveBinding {
+            vertex_buffer, material_binding, index_buffer,
+            index_format: self.indices.format(),
+            index_count: self.indices.len() as u32,
+            render_queue: self.material.render_queue,
+        }
     }
 }
 
 pub struct Mesh {
+    // index of the mesh in the gltf, which differs from its position once meshes without instances are skipped
+    pub gltf_mesh_idx: usize,
     pub primitives: Vec<Primitive>,
     pub instances: Vec<Instance>,
+    // name of the scene node each instance came from
+    pub instance_names: Vec<Option<String>>,
 }
 
 pub struct MeshBinding {
     pub primitives: Vec<PrimitiveBinding>,
     pub instance_buffer: wgpu::Buffer,
     pub instance_count: u32,
+    instances: Vec<Instance>,
+    // per instance bitmask of the primitives that should be drawn, bit n = primitive n
+    primitive_masks: Vec<u64>,
+    // per instance result of the last frustum cull
+    in_frustum: Vec<bool>,
+    local_bounds: Option<Aabb>,
+    // visible instances are stored in the instance buffer grouped by their mask, so each group is one draw call
+    pub instance_groups: Vec<(u64, Range<u32>)>,
+    // every instance, frustum culled or not, grouped the same way after the camera's instances; shadow
+    // casters outside of the view still shadow what's in it
+    pub shadow_instance_groups: Vec<(u64, Range<u32>)>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
 }
 
 impl Default for Mesh {
     fn default() -> Self {
         Self {
+            gltf_mesh_idx: 0,
             primitives: vec![Primitive::default()],
             instances: vec![Instance::default()],
+            instance_names: vec![None],
         }
     }
 }
 
 impl Mesh {
-    pub fn upload(&self, device: &wgpu::Device, queue: &wgpu::Queue, material_bind_group_layout: &wgpu::BindGroupLayout) -> MeshBinding {
+    // object space bounds of all primitives
+    pub fn local_bounds(&self) -> Option<Aabb> {
+        Aabb::from_points(self.primitives.iter().flat_map(|p| p.vertices.iter().map(|v| v.position.into())))
+    }
+
+    pub fn world_bounds(&self) -> Option<Aabb> {
+        let local = self.local_bounds()?;
+        self.instances.iter()
+            .map(|instance| local.transform(&instance.transform()))
+            .reduce(|a, b| a.union(&b))
+    }
+
+    pub fn upload<'a>(
+        &'a self, device: &wgpu::Device, queue: &wgpu::Queue,
+        material_bind_group_layout: &wgpu::BindGroupLayout, materials: &mut MaterialCache<'a>,
+    ) -> MeshBinding {
+        // the camera's instances followed by the shadow casters, see MeshBinding::shadow_instance_groups
+        let both: Vec<Instance> = self.instances.iter().chain(&self.instances).copied().collect();
         let instance_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&self.instances),
-                usage: wgpu::BufferUsages::VERTEX,
+                contents: bytemuck::cast_slice(&both),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             }
         );
         let primitives = self.primitives.iter().map(|primitive| {
-            primitive.upload(device, queue, material_bind_group_layout)
+            primitive.upload(device, queue, material_bind_group_layout, materials)
         }).collect();
-        MeshBinding { primitives, instance_buffer, instance_count: self.instances.len() as u32 }
+        let instance_count = self.instances.len() as u32;
+        MeshBinding {
+            primitives,
+            instance_buffer,
+            instance_count,
+            instances: self.instances.clone(),
+            primitive_masks: vec![u64::MAX; self.instances.len()],
+            in_frustum: vec![true; self.instances.len()],
+            local_bounds: self.local_bounds(),
+            instance_groups: vec![(u64::MAX, 0..instance_count)],
+            shadow_instance_groups: vec![(u64::MAX, instance_count..2 * instance_count)],
+        }
+    }
+}
+
+impl MeshBinding {
+    pub fn is_primitive_visible(mask: u64, primitive_idx: usize) -> bool {
+        // only the first 64 primitives can be masked
+        primitive_idx >= u64::BITS as usize || mask & (1 << primitive_idx) != 0
+    }
+
+    pub fn set_primitive_mask(&mut self, queue: &wgpu::Queue, instance_idx: usize, mask: u64) -> Result<(), String> {
+        let instance_count = self.primitive_masks.len();
+        let current = self.primitive_masks.get_mut(instance_idx)
+            .ok_or(format!("no instance {}, the mesh has {}", instance_idx, instance_count))?;
+        if *current != mask {
+            *current = mask;
+            self.write_instances(queue);
+        }
+        Ok(())
+    }
+
+    // drops the instances outside of the frustum from the instance buffer
+    pub fn cull(&mut self, queue: &wgpu::Queue, frustum: &Frustum) -> CullStats {
+        let in_frustum: Vec<bool> = match &self.local_bounds {
+            Some(local) => self.instances.iter()
+                .map(|instance| frustum.intersects(&local.transform(&instance.transform())))
+                .collect(),
+            None => vec![true; self.instances.len()],
+        };
+        if in_frustum != self.in_frustum {
+            self.in_frustum = in_frustum;
+            self.write_instances(queue);
+        }
+        let drawn = self.in_frustum.iter().filter(|v| **v).count() as u32;
+        CullStats { drawn, culled: self.instance_count - drawn }
+    }
+
+    fn write_instances(&mut self, queue: &wgpu::Queue) {
+        let visible = self.write_instance_range(queue, 0, |i| self.in_frustum[i]);
+        self.instance_groups = visible;
+        let shadow_casters = self.write_instance_range(queue, self.instance_count, |_| true);
+        self.shadow_instance_groups = shadow_casters;
+    }
+
+    // writes the selected instances sorted by mask from the first slot on, returns the groups
+    fn write_instance_range(&self, queue: &wgpu::Queue, first_slot: u32, selected: impl Fn(usize) -> bool) -> Vec<(u64, Range<u32>)> {
+        let mut order: Vec<usize> = (0..self.instances.len()).filter(|i| selected(*i)).collect();
+        order.sort_by_key(|i| self.primitive_masks[*i]);
+
+        let mut groups: Vec<(u64, Range<u32>)> = vec![];
+        for (idx, instance_idx) in order.iter().enumerate() {
+            let slot = first_slot + idx as u32;
+            let mask = self.primitive_masks[*instance_idx];
+            match groups.last_mut() {
+                Some((group_mask, range)) if *group_mask == mask => range.end = slot + 1,
+                _ => groups.push((mask, slot..slot + 1)),
+            }
+        }
+
+        let sorted: Vec<Instance> = order.iter().map(|i| self.instances[*i]).collect();
+        if !sorted.is_empty() {
+            let offset = first_slot as u64 * size_of::<Instance>() as u64;
+            queue.write_buffer(&self.instance_buffer, offset, bytemuck::cast_slice(&sorted));
+        }
+        groups
     }
 }
 
@@ -673,17 +850,6 @@ impl MaterialPipeline {
         Self { render_pipeline, material_bind_group_layout }
     }
 
-    pub fn rebuild_pipeline(
-        &mut self,
-        device: &wgpu::Device,
-        surface_config: &wgpu::SurfaceConfiguration,
-        camera_bind_group_layout: &wgpu::BindGroupLayout,
-        lights_bind_group_layout: &wgpu::BindGroupLayout,
-        diffuse_irradiance_bind_group_layout: &wgpu::BindGroupLayout,
-    ) {
-        self.render_pipeline = Self::build_pipeline(device, surface_config, camera_bind_group_layout, lights_bind_group_layout, &self.material_bind_group_layout, diffuse_irradiance_bind_group_layout);
-    }
-    
     pub fn build_pipeline(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
@@ -699,7 +865,10 @@ impl MaterialPipeline {
             bind_group_layouts,
             push_constant_ranges: &[],
         });
-        let shader_module = crate::renderer::utils::create_shader_module(device, "src/renderer/shaders/pbr.wgsl");
+        let storage = lights::punctual_lights_in_storage(device);
+        let shader_module = crate::renderer::utils::create_shader_module_with(device, "src/renderer/shaders/pbr.wgsl", |source| {
+            if storage { source } else { lights::uniform_punctual_lights_shader(source) }
+        });
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("PBR Material Render Pipeline"),
             layout: Some(&render_pipeline_layout),
@@ -784,13 +953,23 @@ impl MaterialPipeline {
             render_pass.set_bind_group(1u32, &world_binding.lights_binding.bind_group, &[]);
             render_pass.set_bind_group(3u32, &world_binding.environment_map_binding.bind_group, &[]);
 
-            for mesh in &world_binding.pbr_mesh_bindings {
+            // stable sort, so primitives in the same queue keep the scene order
+            let mut draws: Vec<(i32, &MeshBinding, usize, &PrimitiveBinding)> = world_binding.pbr_mesh_bindings.iter()
+                .flat_map(|mesh| mesh.primitives.iter().enumerate().map(move |(primitive_idx, primitive)| {
+                    (primitive.render_queue, mesh, primitive_idx, primitive)
+                }))
+                .collect();
+            draws.sort_by_key(|(render_queue, ..)| *render_queue);
+
+            for (_, mesh, primitive_idx, primitive) in draws {
                 render_pass.set_vertex_buffer(0, mesh.instance_buffer.slice(..));
-                for primitive in &mesh.primitives {
-                    render_pass.set_bind_group(2u32, &primitive.material_binding.bind_group, &[]);
-                    render_pass.set_vertex_buffer(1u32, primitive.vertex_buffer.slice(..));
-                    render_pass.set_index_buffer(primitive.index_buffer.slice(..), primitive.index_format);
-                    render_pass.draw_indexed(0..primitive.index_count, 0, 0..mesh.instance_count);
+                render_pass.set_bind_group(2u32, &primitive.material_binding.bind_group, &[]);
+                render_pass.set_vertex_buffer(1u32, primitive.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(primitive.index_buffer.slice(..), primitive.index_format);
+                for (mask, instances) in &mesh.instance_groups {
+                    if MeshBinding::is_primitive_visible(*mask, primitive_idx) {
+                        render_pass.draw_indexed(0..primitive.index_count, 0, instances.clone());
+                    }
                 }
             }
         }
@@ -799,3 +978,23 @@ impl MaterialPipeline {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_meshes_get_u16_indices() {
+        let indices = VertexIndices::narrowed(vec![0, 1, 65535]);
+        assert!(matches!(indices, VertexIndices::U16(ref v) if v == &[0, 1, 65535]));
+        assert_eq!(indices.format(), wgpu::IndexFormat::Uint16);
+        assert_eq!(indices.as_bytes().len(), 6);
+    }
+
+    #[test]
+    fn large_meshes_keep_u32_indices() {
+        let indices = VertexIndices::narrowed(vec![0, 1, 65536]);
+        assert!(matches!(indices, VertexIndices::U32(ref v) if v == &[0, 1, 65536]));
+        assert_eq!(indices.format(), wgpu::IndexFormat::Uint32);
+    }
+}
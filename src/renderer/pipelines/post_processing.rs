@@ -1,17 +1,148 @@
 use wgpu::util::DeviceExt;
 
-use crate::renderer::msaa_textures::MSAATextures;
+use crate::renderer::{color_lut::ColorLutTexture, depth_texture::DepthTexture, msaa_textures::MSAATextures, renderer::WorldBinding};
 
-use super::skybox::SkyboxOutputTexture;
+use super::{shadow::ShadowMap, skybox::SkyboxOutputTexture};
 
 const INDICES: &[u16] = &[
     0, 2, 1,
     3, 2, 0,
 ];
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    None,
+    Reinhard,
+    // narkowicz's fit of the aces rrt + odt
+    Aces,
+}
+
+impl Tonemapper {
+    pub fn next(self) -> Self {
+        match self {
+            Tonemapper::None => Tonemapper::Reinhard,
+            Tonemapper::Reinhard => Tonemapper::Aces,
+            Tonemapper::Aces => Tonemapper::None,
+        }
+    }
+}
+
+/*
+* What the post pass puts on screen. Everything but Final shows one of the frame's
+* intermediate targets as is, switching is a uniform write.
+*/
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    Final,
+    // the msaa resolve of the meshes, exposed but not tonemapped or graded
+    Resolve,
+    // the skybox target, exposed but not tonemapped or graded
+    Skybox,
+    // resolve alpha, how much of each pixel the meshes cover
+    Coverage,
+    // linear view depth of the first msaa sample, white at debug_depth_range
+    Depth,
+    // the sun shadow map stretched over the screen, white is far from the sun
+    ShadowMap,
+}
+
+impl DebugView {
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Final => DebugView::Resolve,
+            DebugView::Resolve => DebugView::Skybox,
+            DebugView::Skybox => DebugView::Coverage,
+            DebugView::Coverage => DebugView::Depth,
+            DebugView::Depth => DebugView::ShadowMap,
+            DebugView::ShadowMap => DebugView::Final,
+        }
+    }
+}
+
+// every intermediate target of the frame the post pass can read
+pub struct PostProcessingSources<'a> {
+    pub skybox_texture: &'a SkyboxOutputTexture,
+    pub msaa_textures: &'a MSAATextures,
+    pub depth_texture: &'a DepthTexture,
+    pub shadow_map: &'a ShadowMap,
+    pub color_lut: &'a ColorLutTexture,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugParams {
+    view: u32,
+    depth_range: f32,
+    znear: f32,
+    zfar: f32,
+}
+
+/*
+* Post settings travel with the camera, so each view can have its own exposure and tonemapping
+*/
+#[derive(Copy, Clone, Debug)]
+pub struct PostSettings {
+    // manual exposure as EV at ISO 100, scene values are in physical units (lux, nits)
+    pub ev100: f32,
+    pub tonemapper: Tonemapper,
+    // 0 is ungraded, 1 the full color lut, in between blends (e.g. fading a grade in)
+    pub lut_weight: f32,
+    pub debug_view: DebugView,
+    // view distance shown as white in the depth debug view, 0 for the far plane
+    pub debug_depth_range: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            // same brightness as the old fixed exposure factor of 2.0
+            ev100: -1.263,
+            tonemapper: Tonemapper::Reinhard,
+            lut_weight: 0.0,
+            debug_view: DebugView::Final,
+            debug_depth_range: 0.0,
+        }
+    }
+}
+
+impl PostSettings {
+    /*
+    * Scale from scene luminance to the tonemapper input, 1 / max luminance of a
+    * saturation based sensor (lagarde & de rousiers, moving frostbite to pbr)
+    */
+    pub fn exposure(&self) -> f32 {
+        1.0 / (1.2 * 2f32.powf(self.ev100))
+    }
+
+    fn tonemapper_index(&self) -> u32 {
+        match self.tonemapper {
+            Tonemapper::None => 0,
+            Tonemapper::Reinhard => 1,
+            Tonemapper::Aces => 2,
+        }
+    }
+
+    fn debug_params(&self, znear: f32, zfar: f32) -> DebugParams {
+        let view = match self.debug_view {
+            DebugView::Final => 0,
+            DebugView::Resolve => 1,
+            DebugView::Skybox => 2,
+            DebugView::Coverage => 3,
+            DebugView::Depth => 4,
+            DebugView::ShadowMap => 5,
+        };
+        let depth_range = if self.debug_depth_range > 0.0 { self.debug_depth_range } else { zfar };
+        DebugParams { view, depth_range, znear, zfar }
+    }
+}
+
 struct PostProcessingInputs {}
 struct PostProcessingInputsBinding {
     bind_group: wgpu::BindGroup,
+    exposure_buffer: wgpu::Buffer,
+    tonemapper_buffer: wgpu::Buffer,
+    lut_weight_buffer: wgpu::Buffer,
+    debug_params_buffer: wgpu::Buffer,
 }
 impl PostProcessingInputs {
     pub fn desc() -> wgpu::BindGroupLayoutDescriptor<'static> {
@@ -49,6 +180,92 @@ impl PostProcessingInputs {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        multisampled: false
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: true
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 12,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false
+                    },
+                    count: None,
+                },
             ],
             label: Some("Post Processing Inputs Bind Group Layout"),
         }
@@ -57,33 +274,102 @@ impl PostProcessingInputs {
     pub fn upload(
         device: &wgpu::Device,
         bind_group_layout: &wgpu::BindGroupLayout,
-        skybox_texture: &SkyboxOutputTexture,
-        msaa_textures: &MSAATextures,
+        sources: &PostProcessingSources,
+        settings: &PostSettings,
     ) -> PostProcessingInputsBinding {
+        let exposure_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Post Processing Exposure Buffer"),
+                contents: bytemuck::cast_slice(&[settings.exposure()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let tonemapper_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Post Processing Tonemapper Buffer"),
+                contents: bytemuck::cast_slice(&[settings.tonemapper_index()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let lut_weight_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Post Processing Lut Weight Buffer"),
+                contents: bytemuck::cast_slice(&[settings.lut_weight]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        // the clip planes are filled in by the first update_settings
+        let debug_params_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Post Processing Debug Params Buffer"),
+                contents: bytemuck::cast_slice(&[settings.debug_params(0.0, 0.0)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&skybox_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&sources.skybox_texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&skybox_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&sources.skybox_texture.sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&msaa_textures.resolve_texture_view),
+                    resource: wgpu::BindingResource::TextureView(&sources.msaa_textures.resolve_texture_view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&msaa_textures.resolve_sampler),
+                    resource: wgpu::BindingResource::Sampler(&sources.msaa_textures.resolve_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: exposure_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: tonemapper_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: lut_weight_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&sources.color_lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&sources.color_lut.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: sources.color_lut.params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: debug_params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: wgpu::BindingResource::TextureView(&sources.depth_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: wgpu::BindingResource::TextureView(&sources.shadow_map.view),
                 },
             ],
             label: Some("Post Processing Inputs Bind Group"),
         });
 
-        PostProcessingInputsBinding { bind_group }
+        PostProcessingInputsBinding { bind_group, exposure_buffer, tonemapper_buffer, lut_weight_buffer, debug_params_buffer }
     }
 }
 
@@ -91,17 +377,17 @@ pub struct PostProcessingPipeline {
     render_pipeline: wgpu::RenderPipeline,
     index_buffer: wgpu::Buffer,
     inputs_binding: PostProcessingInputsBinding,
-    inputs_bind_group_layout: wgpu::BindGroupLayout,
 }
 impl PostProcessingPipeline {
     pub fn new(
         device: &wgpu::Device,
         surface_config: &wgpu::SurfaceConfiguration,
-        skybox_texture: &SkyboxOutputTexture,
-        msaa_textures: &MSAATextures,
+        sources: &PostProcessingSources,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        settings: &PostSettings,
     ) -> Self {
         let inputs_bind_group_layout = device.create_bind_group_layout(&PostProcessingInputs::desc());
-        let bind_group_layouts = &[&inputs_bind_group_layout];
+        let bind_group_layouts = &[&inputs_bind_group_layout, camera_bind_group_layout];
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Post Processing Pipeline Layout"),
             bind_group_layouts,
@@ -145,9 +431,17 @@ impl PostProcessingPipeline {
             }
         );
 
-        let inputs_binding = PostProcessingInputs::upload(device, &inputs_bind_group_layout, skybox_texture, msaa_textures);
+        let inputs_binding = PostProcessingInputs::upload(device, &inputs_bind_group_layout, sources, settings);
+
+        Self { render_pipeline, index_buffer, inputs_binding }
+    }
 
-        Self { render_pipeline, index_buffer, inputs_binding, inputs_bind_group_layout }
+    // znear / zfar are the camera's, for linearizing depth in the depth debug view
+    pub fn update_settings(&self, queue: &wgpu::Queue, settings: &PostSettings, znear: f32, zfar: f32) {
+        queue.write_buffer(&self.inputs_binding.exposure_buffer, 0, bytemuck::cast_slice(&[settings.exposure()]));
+        queue.write_buffer(&self.inputs_binding.tonemapper_buffer, 0, bytemuck::cast_slice(&[settings.tonemapper_index()]));
+        queue.write_buffer(&self.inputs_binding.lut_weight_buffer, 0, bytemuck::cast_slice(&[settings.lut_weight]));
+        queue.write_buffer(&self.inputs_binding.debug_params_buffer, 0, bytemuck::cast_slice(&[settings.debug_params(znear, zfar)]));
     }
 
     pub fn render(
@@ -155,6 +449,7 @@ impl PostProcessingPipeline {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         output_texture_view: &wgpu::TextureView,
+        world_binding: &WorldBinding,
     ) -> Result<(), wgpu::SurfaceError> {
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Post Processing Render Encoder"),
@@ -178,6 +473,7 @@ impl PostProcessingPipeline {
 
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0u32, &self.inputs_binding.bind_group, &[]);
+            render_pass.set_bind_group(1u32, &world_binding.camera_binding.bind_group, &[]);
             render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
             render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..1);
         }
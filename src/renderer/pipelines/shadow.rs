@@ -0,0 +1,163 @@
+use crate::renderer::{bind_group_layouts, lights::LightsBinding, renderer::WorldBinding};
+
+use super::pbr::{Instance, MeshBinding, Vertex};
+
+pub struct ShadowMap {
+    // only used through the view
+    _texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+}
+
+impl ShadowMap {
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn new(device: &wgpu::Device, resolution: u32) -> Self {
+        let size = wgpu::Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: 1,
+        };
+        let desc = wgpu::TextureDescriptor {
+            label: Some("Shadow Map Texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // linear filtering of the comparison results, on top of the pcf taps in the shader
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Map Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        Self { _texture: texture, view, sampler }
+    }
+}
+
+/*
+* Renders the depth of every mesh as seen from the sun into the shadow map.
+* The light view projection is shared with the pbr pipeline through the lights binding.
+*/
+pub struct ShadowPipeline {
+    render_pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+}
+
+impl ShadowPipeline {
+    pub fn new(device: &wgpu::Device, lights_binding: &LightsBinding) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Shadow Caster Bind Group Layout"),
+            entries: bind_group_layouts::shadow_caster::ENTRIES,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Shadow Caster Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: bind_group_layouts::shadow_caster::LIGHT_VIEW_PROJ,
+                    resource: lights_binding.light_view_proj_buffer().as_entire_binding(),
+                },
+            ],
+        });
+
+        let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader_module = crate::renderer::utils::create_shader_module(device, "src/renderer/shaders/shadow.wgsl");
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Render Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader_module,
+                entry_point: "vs_main",
+                buffers: &[Instance::desc(), Vertex::desc()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: ShadowMap::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                // against shadow acne on surfaces facing away from the sun
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self { render_pipeline, bind_group }
+    }
+
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        shadow_map: &ShadowMap,
+        world_binding: &WorldBinding,
+    ) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Shadow Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Render Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &shadow_map.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(0u32, &self.bind_group, &[]);
+
+            // the unculled instances, casters outside of the camera frustum still cast into it
+            for mesh in &world_binding.pbr_mesh_bindings {
+                render_pass.set_vertex_buffer(0, mesh.instance_buffer.slice(..));
+                for (primitive_idx, primitive) in mesh.primitives.iter().enumerate() {
+                    render_pass.set_vertex_buffer(1u32, primitive.vertex_buffer.slice(..));
+                    render_pass.set_index_buffer(primitive.index_buffer.slice(..), primitive.index_format);
+                    for (mask, instances) in &mesh.shadow_instance_groups {
+                        if MeshBinding::is_primitive_visible(*mask, primitive_idx) {
+                            render_pass.draw_indexed(0..primitive.index_count, 0, instances.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
@@ -8,6 +8,8 @@ const INDICES: &[u16] = &[
 ];
 
 pub struct SkyboxOutputTexture {
+    // kept alive for the view
+    #[allow(dead_code)]
     texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -204,7 +206,7 @@ pub fn create_test_cubemap_texture(
             &face_data,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(bytes_per_pixel as u32 * size),
+                bytes_per_row: Some(bytes_per_pixel * size),
                 rows_per_image: Some(size),
             },
             wgpu::Extent3d {
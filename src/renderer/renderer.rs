@@ -1,15 +1,16 @@
-use std::{fmt::Debug, fs::File, io::Read, sync::Arc};
+use std::{collections::{hash_map::Entry, HashMap}, fmt::Debug, fs::File, io::Read, sync::Arc};
 
 use image::ImageReader;
 use winit::window::Window;
 
 use super::{
-    camera::{Camera, CameraBinding, CameraUniform}, depth_texture::DepthTexture, lights::{Lights, LightsBinding}, msaa_textures::MSAATextures, pipelines::{
+    bounds::{Aabb, Frustum}, color_lut::{ColorLut, ColorLutTexture}, utils,
+    camera::{Camera, CameraBinding, CameraUniform}, depth_texture::DepthTexture, frame_globals::FrameClock, lights::{Lights, LightsBinding}, msaa_textures::MSAATextures, pipelines::{
         diffuse_irradiance::DiffuseIrradiancePipeline, env_prefilter::EnvPrefilterPipeline, equirectangular::{
             render_cubemap, write_texture_to_file, FaceRotation,
         }, pbr::{
-            MaterialPipeline, Mesh, MeshBinding, SamplerOptions
-        }, post_processing::PostProcessingPipeline, skybox::{create_test_cubemap_texture, SkyboxPipeline, SkyboxOutputTexture}
+            CullStats, MaterialCache, MaterialPipeline, Mesh, MeshBinding, PrimitiveBinding, SamplerOptions, TextureSlot
+        }, post_processing::{PostProcessingPipeline, PostProcessingSources}, shadow::{ShadowMap, ShadowPipeline}, skybox::{create_test_cubemap_texture, SkyboxPipeline, SkyboxOutputTexture}
     }, wgpu_context::WgpuContext
 };
 
@@ -195,12 +196,14 @@ impl EnvironmentMapBinding {
                         SamplerOptions {
                             mag_filter: wgpu::FilterMode::Linear,
                             min_filter: wgpu::FilterMode::Linear,
+                            mipmap_filter: None,
                             address_mode_u: wgpu::AddressMode::ClampToEdge,
                             address_mode_v: wgpu::AddressMode::ClampToEdge
                         }
                     )
                 ),
-                true
+                true,
+                false
             );
             (t.view, t.sampler)
         };
@@ -239,11 +242,24 @@ impl EnvironmentMapBinding {
     }
 }
 
+// the pipelines reload_pipelines rebuilds, the env map precompute shaders only run at startup
+const HOT_RELOADED_SHADERS: &[&str] = &[
+    "src/renderer/shaders/pbr.wgsl",
+    "src/renderer/shaders/skybox.wgsl",
+    "src/renderer/shaders/shadow.wgsl",
+    "src/renderer/shaders/post_processing.wgsl",
+];
+
+// shadow map width and height in texels
+const SHADOW_MAP_RESOLUTION: u32 = 2048;
+
 pub struct World {
     pub camera: Camera,
     pub lights: Lights,
     pub pbr_meshes: Vec<Mesh>,
     pub environment_map: image::DynamicImage,
+    // world space bounds of every mesh instance, None for an empty scene
+    pub bounds: Option<Aabb>,
 }
 pub struct WorldBinding {
     pub camera_binding: CameraBinding,
@@ -258,14 +274,16 @@ impl World {
         queue: &wgpu::Queue,
         pbr_material_bind_group_layout: &wgpu::BindGroupLayout,
         camera_bind_group_layout: &wgpu::BindGroupLayout,
-        lights_bind_group_layout: &wgpu::BindGroupLayout,
+        // uploaded by the caller, it depends on the shadow map
+        lights_binding: LightsBinding,
         environment_map_bind_group_layout: &wgpu::BindGroupLayout,
     ) -> WorldBinding {
         let camera_binding = self.camera.to_camera_uniform().upload(device, camera_bind_group_layout);
-        let lights_binding = self.lights.upload(device, lights_bind_group_layout);
+        let mut materials = MaterialCache::new();
         let pbr_mesh_bindings = self.pbr_meshes.iter().map(|mesh| {
-            mesh.upload(device, queue, pbr_material_bind_group_layout)
+            mesh.upload(device, queue, pbr_material_bind_group_layout, &mut materials)
         }).collect();
+        log::info!("{} primitive materials, {} uploaded after merging duplicates", materials.requested, materials.uploaded);
         let environment_map_binding = EnvironmentMapBinding::from_image(device, queue, self.environment_map.clone(), environment_map_bind_group_layout);
 
         WorldBinding { camera_binding, lights_binding, pbr_mesh_bindings, environment_map_binding }
@@ -278,6 +296,8 @@ pub struct Renderer<'surface> {
     skybox_pipeline: SkyboxPipeline,
     pbr_material_pipeline: MaterialPipeline,
     post_processing_pipeline: PostProcessingPipeline,
+    shadow_pipeline: ShadowPipeline,
+    shadow_map: ShadowMap,
     world_binding: WorldBinding,
     world: World,
     camera_bind_group_layout: wgpu::BindGroupLayout,
@@ -285,18 +305,26 @@ pub struct Renderer<'surface> {
     environment_map_bind_group_layout: wgpu::BindGroupLayout,
     msaa_textures: MSAATextures,
     skybox_texture: SkyboxOutputTexture,
+    color_lut: ColorLutTexture,
+    pending_resize: Option<winit::dpi::PhysicalSize<u32>>,
+    frame_clock: FrameClock,
+    cull_stats: CullStats,
+    // node name -> (gltf mesh_idx, instance_idx)
+    node_names: HashMap<String, (usize, usize)>,
+    // gltf mesh_idx -> index of its binding, meshes without instances aren't uploaded
+    mesh_indices: HashMap<usize, usize>,
 }
 impl<'surface> Renderer<'surface> {
     pub async fn new(
         window: Arc<Window>,
         pbr_meshes: Vec<Mesh>,
-    ) -> Self {
-        let wgpu_context = WgpuContext::new(window).await;
+    ) -> Result<Self, String> {
+        let wgpu_context = WgpuContext::new(window).await?;
         let depth_texture = DepthTexture::new(&wgpu_context.device, &wgpu_context.surface_config);
         let msaa_textures = MSAATextures::new(&wgpu_context.device, &wgpu_context.surface_config);
         let skybox_texture = SkyboxOutputTexture::new(&wgpu_context.device, &wgpu_context.surface_config);
         let camera_bind_group_layout = wgpu_context.device.create_bind_group_layout(&CameraUniform::desc());
-        let lights_bind_group_layout = wgpu_context.device.create_bind_group_layout(&Lights::desc());
+        let lights_bind_group_layout = Lights::create_bind_group_layout(&wgpu_context.device);
         let environment_map_bind_group_layout = wgpu_context.device.create_bind_group_layout(&EnvironmentMapBinding::desc());
 
         let skybox_pipeline = SkyboxPipeline::new(
@@ -308,12 +336,20 @@ impl<'surface> Renderer<'surface> {
             &camera_bind_group_layout, &lights_bind_group_layout,
             &environment_map_bind_group_layout
         );
+        let mut camera = Camera::new(&wgpu_context.surface_config);
+        let (color_lut, lut_loaded) = ColorLutTexture::from_env(&wgpu_context.device, &wgpu_context.queue);
+        if lut_loaded {
+            camera.post_settings.lut_weight = 1.0;
+        }
+        let shadow_map = ShadowMap::new(&wgpu_context.device, SHADOW_MAP_RESOLUTION);
         let post_processing_pipeline = PostProcessingPipeline::new(
             &wgpu_context.device, &wgpu_context.surface_config,
-            &skybox_texture, &msaa_textures
+            &PostProcessingSources {
+                skybox_texture: &skybox_texture, msaa_textures: &msaa_textures, depth_texture: &depth_texture,
+                shadow_map: &shadow_map, color_lut: &color_lut,
+            },
+            &camera_bind_group_layout, &camera.post_settings
         );
-
-        let camera = Camera::new(&wgpu_context.surface_config);
         let lights = Lights::default();
         
         let environment_map = {
@@ -324,50 +360,118 @@ impl<'surface> Renderer<'surface> {
             img
         };
 
-        let world = World { camera, lights, pbr_meshes, environment_map };
+        let node_names = index_node_names(&pbr_meshes);
+        let mesh_indices = pbr_meshes.iter().enumerate().map(|(i, mesh)| (mesh.gltf_mesh_idx, i)).collect();
+        let bounds = pbr_meshes.iter().filter_map(Mesh::world_bounds).reduce(|a, b| a.union(&b));
+        let world = World { camera, lights, pbr_meshes, environment_map, bounds };
+        let lights_binding = world.lights.upload(&wgpu_context.device, &lights_bind_group_layout, &shadow_map);
         let world_binding = world.upload(
             &wgpu_context.device, &wgpu_context.queue,
             &pbr_material_pipeline.material_bind_group_layout,
-            &camera_bind_group_layout, &lights_bind_group_layout,
+            &camera_bind_group_layout, lights_binding,
             &environment_map_bind_group_layout
         );
+        let shadow_pipeline = ShadowPipeline::new(&wgpu_context.device, &world_binding.lights_binding);
         
-        Self {
+        let mut renderer = Self {
             wgpu_context, depth_texture, skybox_pipeline,
             pbr_material_pipeline, world_binding, world,
             camera_bind_group_layout, lights_bind_group_layout,
-            environment_map_bind_group_layout, msaa_textures, skybox_texture,
-            post_processing_pipeline
-        }
+            environment_map_bind_group_layout, msaa_textures, skybox_texture, color_lut,
+            post_processing_pipeline, pending_resize: None, frame_clock: FrameClock::new(),
+            cull_stats: CullStats::default(), shadow_pipeline, shadow_map, node_names,
+            mesh_indices,
+        };
+        // fits the shadow map (and clip planes) to the initial view
+        renderer.update_camera();
+        Ok(renderer)
     }
 
-    pub fn reload_pbr_pipeline(&mut self) -> Result<(), wgpu::SurfaceError> {
-        self.pbr_material_pipeline.rebuild_pipeline(
-            &self.wgpu_context.device, &self.wgpu_context.surface_config,
-            &self.camera_bind_group_layout, &self.lights_bind_group_layout,
-            &self.environment_map_bind_group_layout
-        );
-        self.render()
+    /*
+    * Rebuilds the pipelines from the shader sources on disk. Nothing is swapped unless every
+    * shader compiles and every pipeline validates, on error the old pipelines keep rendering
+    * and the diagnostics are returned.
+    * The shared bind group layouts are generated by build.rs, a shader that adds, removes or retypes
+    * one of their bindings fails validation here until the app is rebuilt.
+    */
+    pub fn reload_pipelines(&mut self) -> Result<(), String> {
+        let device = &self.wgpu_context.device;
+        for path in HOT_RELOADED_SHADERS {
+            utils::check_shader(device, path)?;
+        }
+        let (pbr_render_pipeline, skybox_pipeline, shadow_pipeline, post_processing_pipeline) = utils::validated(device, || (
+            MaterialPipeline::build_pipeline(
+                device, &self.wgpu_context.surface_config,
+                &self.camera_bind_group_layout, &self.lights_bind_group_layout,
+                &self.pbr_material_pipeline.material_bind_group_layout, &self.environment_map_bind_group_layout
+            ),
+            SkyboxPipeline::new(
+                device, &self.wgpu_context.surface_config,
+                &self.camera_bind_group_layout, &self.environment_map_bind_group_layout
+            ),
+            ShadowPipeline::new(device, &self.world_binding.lights_binding),
+            PostProcessingPipeline::new(
+                device, &self.wgpu_context.surface_config,
+                &self.post_processing_sources(), &self.camera_bind_group_layout, &self.world.camera.post_settings
+            ),
+        )).map_err(|e| format!("{}\n(bind group layout changes need a rebuild, see build.rs)", e))?;
+
+        self.pbr_material_pipeline.render_pipeline = pbr_render_pipeline;
+        self.skybox_pipeline = skybox_pipeline;
+        self.shadow_pipeline = shadow_pipeline;
+        self.post_processing_pipeline = post_processing_pipeline;
+        self.update_post_settings();
+        Ok(())
     }
 
     pub fn render(
-        &self,
+        &mut self,
     ) -> Result<(), wgpu::SurfaceError> {
-        let output = self.wgpu_context.surface.get_current_texture()?;
+        crate::logger::next_frame();
+        self.apply_pending_resize();
+
+        let output = match self.wgpu_context.surface.get_current_texture() {
+            Ok(output) => output,
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                // the window changed size since the last configure, try again next frame at the new size
+                self.skip_frame();
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        if output.texture.width() != self.wgpu_context.surface_config.width
+            || output.texture.height() != self.wgpu_context.surface_config.height
+        {
+            // don't present a frame whose size disagrees with the depth/msaa/skybox targets
+            // (the output is dropped without presenting)
+            self.skip_frame();
+            return Ok(());
+        }
         let output_view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
 
+        let frame_globals = self.frame_clock.tick([
+            self.wgpu_context.surface_config.width as f32,
+            self.wgpu_context.surface_config.height as f32,
+        ]);
+        self.world_binding.camera_binding.update_frame_globals(&frame_globals, &self.wgpu_context.queue);
+
         self.skybox_pipeline.render(
             &self.wgpu_context.device, &self.wgpu_context.queue,
             &self.skybox_texture.view, &self.world_binding,
         )?;
 
+        self.shadow_pipeline.render(
+            &self.wgpu_context.device, &self.wgpu_context.queue,
+            &self.shadow_map, &self.world_binding
+        );
+
         self.pbr_material_pipeline.render(
             &self.wgpu_context.device, &self.wgpu_context.queue, &self.msaa_textures,
             &self.depth_texture.view, &self.world_binding
         );
 
         self.post_processing_pipeline.render(
-            &self.wgpu_context.device, &self.wgpu_context.queue, &output_view
+            &self.wgpu_context.device, &self.wgpu_context.queue, &output_view, &self.world_binding
         )?;
 
         output.present();
@@ -375,8 +479,28 @@ impl<'surface> Renderer<'surface> {
         Ok(())
     }
 
+    /*
+    * Resizing is deferred to the start of the next render, so all size dependent targets and the
+    * camera aspect are recreated together, and a burst of resize events collapses into the last one.
+    */
     pub fn resize(&mut self, new_size: Option<winit::dpi::PhysicalSize<u32>>) {
-        let new_size = new_size.unwrap_or(self.wgpu_context.window.inner_size());
+        self.pending_resize = Some(new_size.unwrap_or(self.wgpu_context.window.inner_size()));
+    }
+
+    fn skip_frame(&mut self) {
+        self.resize(None);
+        let size = self.wgpu_context.window.inner_size();
+        // a minimized window has no size, it will get a resize event once it's restored
+        if size.width > 0 && size.height > 0 {
+            self.wgpu_context.window.request_redraw();
+        }
+    }
+
+    fn apply_pending_resize(&mut self) {
+        let new_size = match self.pending_resize.take() {
+            Some(size) => size,
+            None => return,
+        };
         if new_size.width > 0 && new_size.height > 0 {
             self.wgpu_context.surface_config.width = new_size.width;
             self.wgpu_context.surface_config.height = new_size.height;
@@ -386,19 +510,196 @@ impl<'surface> Renderer<'surface> {
             self.msaa_textures = MSAATextures::new(&self.wgpu_context.device, &self.wgpu_context.surface_config);
             self.post_processing_pipeline = PostProcessingPipeline::new(
                 &self.wgpu_context.device, &self.wgpu_context.surface_config,
-                &self.skybox_texture, &self.msaa_textures
+                &self.post_processing_sources(), &self.camera_bind_group_layout, &self.world.camera.post_settings
             );
             self.world.camera.aspect = self.wgpu_context.surface_config.width as f32 / self.wgpu_context.surface_config.height as f32;
             self.update_camera();
         }
     }
 
+    // the binding of a gltf mesh, an error for meshes that aren't drawn
+    fn mesh_binding_idx(&self, mesh_idx: usize) -> Result<usize, String> {
+        self.mesh_indices.get(&mesh_idx).copied()
+            .ok_or(format!("gltf mesh {} isn't drawn, no node in the scene instances it", mesh_idx))
+    }
+
+    /*
+    * Hides primitives of a single mesh instance, bit n of the mask controls primitive n.
+    * mesh_idx is the gltf mesh index, instances are numbered in the order the gltf scene traversal produced them.
+    */
+    pub fn set_primitive_mask(&mut self, mesh_idx: usize, instance_idx: usize, mask: u64) -> Result<(), String> {
+        let binding_idx = self.mesh_binding_idx(mesh_idx)?;
+        self.world_binding.pbr_mesh_bindings[binding_idx].set_primitive_mask(&self.wgpu_context.queue, instance_idx, mask)
+    }
+
+    /*
+    * The gltf mesh and instance indices of the scene node with the given name, for set_primitive_mask etc.
+    * Node names aren't unique in gltf, the first node in traversal order wins.
+    */
+    pub fn find_instance(&self, name: &str) -> Option<(usize, usize)> {
+        self.node_names.get(name).copied()
+    }
+
+    // overrides the material's render queue for one primitive of a gltf mesh, e.g. to draw it after everything else
+    pub fn set_render_queue(&mut self, mesh_idx: usize, primitive_idx: usize, render_queue: i32) -> Result<(), String> {
+        self.primitive_binding_mut(mesh_idx, primitive_idx)?.render_queue = render_queue;
+        Ok(())
+    }
+
+    fn primitive_binding_mut(&mut self, mesh_idx: usize, primitive_idx: usize) -> Result<&mut PrimitiveBinding, String> {
+        let binding_idx = self.primitive_binding_idx(mesh_idx, primitive_idx)?;
+        Ok(&mut self.world_binding.pbr_mesh_bindings[binding_idx].primitives[primitive_idx])
+    }
+
+    // like mesh_binding_idx, also an error if the mesh has no such primitive
+    fn primitive_binding_idx(&self, mesh_idx: usize, primitive_idx: usize) -> Result<usize, String> {
+        let binding_idx = self.mesh_binding_idx(mesh_idx)?;
+        let primitive_count = self.world_binding.pbr_mesh_bindings[binding_idx].primitives.len();
+        if primitive_idx >= primitive_count {
+            return Err(format!("gltf mesh {} has no primitive {}, it has {}", mesh_idx, primitive_idx, primitive_count));
+        }
+        Ok(binding_idx)
+    }
+
+    /*
+    * Updates the contents of a material texture in place (procedural textures, video frames).
+    * mesh_idx is the gltf mesh index, the image must fit inside the texture at the given origin.
+    * A material shared with other primitives is copied first, only this primitive sees the update.
+    */
+    pub fn update_texture(
+        &mut self,
+        mesh_idx: usize,
+        primitive_idx: usize,
+        slot: TextureSlot,
+        origin: (u32, u32),
+        image: &image::DynamicImage,
+    ) -> Result<(), String> {
+        let binding_idx = self.primitive_binding_idx(mesh_idx, primitive_idx)?;
+        self.unshare_material(binding_idx, primitive_idx);
+        let material_binding = &self.world_binding.pbr_mesh_bindings[binding_idx].primitives[primitive_idx].material_binding;
+        material_binding.texture(slot).write_region(&self.wgpu_context.queue, origin, image)
+    }
+
+    /*
+    * Gives the primitive its own material binding if other primitives share it (see MaterialCache).
+    * Shared bindings are never written, so the copy is uploaded from the primitive's material as loaded.
+    * binding_idx is the index into pbr_mesh_bindings (and world.pbr_meshes), see primitive_binding_idx.
+    * Sharing is read off Arc::strong_count, which only works because the primitive bindings hold the
+    * only references: the MaterialCache is dropped at the end of World::upload, and nothing else may keep a clone.
+    */
+    fn unshare_material(&mut self, binding_idx: usize, primitive_idx: usize) {
+        let primitive = &mut self.world_binding.pbr_mesh_bindings[binding_idx].primitives[primitive_idx];
+        if Arc::strong_count(&primitive.material_binding) > 1 {
+            let material = &self.world.pbr_meshes[binding_idx].primitives[primitive_idx].material;
+            primitive.material_binding = Arc::new(material.upload(
+                &self.wgpu_context.device, &self.wgpu_context.queue, &self.pbr_material_pipeline.material_bind_group_layout
+            ));
+        }
+    }
+
+    // swaps the color grading lut, how much of it applies is the camera's post_settings.lut_weight
+    pub fn set_color_lut(&mut self, lut: &ColorLut) {
+        self.color_lut = ColorLutTexture::new(&self.wgpu_context.device, &self.wgpu_context.queue, lut);
+        self.post_processing_pipeline = PostProcessingPipeline::new(
+            &self.wgpu_context.device, &self.wgpu_context.surface_config,
+            &self.post_processing_sources(), &self.camera_bind_group_layout, &self.world.camera.post_settings
+        );
+        self.update_post_settings();
+    }
+
+    fn post_processing_sources(&self) -> PostProcessingSources<'_> {
+        PostProcessingSources {
+            skybox_texture: &self.skybox_texture,
+            msaa_textures: &self.msaa_textures,
+            depth_texture: &self.depth_texture,
+            shadow_map: &self.shadow_map,
+            color_lut: &self.color_lut,
+        }
+    }
+
+    fn update_post_settings(&self) {
+        let camera = &self.world.camera;
+        let (znear, zfar) = camera.clip_range();
+        self.post_processing_pipeline.update_settings(&self.wgpu_context.queue, &camera.post_settings, znear, zfar);
+    }
+
+    /*
+    * Overrides the shadow biases of a primitive's material, None uses the global ones.
+    * mesh_idx is the gltf mesh index, like update_texture this copies a shared material first.
+    */
+    pub fn set_shadow_bias(
+        &mut self,
+        mesh_idx: usize,
+        primitive_idx: usize,
+        depth_bias: Option<f32>,
+        normal_bias: Option<f32>,
+    ) -> Result<(), String> {
+        let binding_idx = self.primitive_binding_idx(mesh_idx, primitive_idx)?;
+        self.unshare_material(binding_idx, primitive_idx);
+        let material_binding = &self.world_binding.pbr_mesh_bindings[binding_idx].primitives[primitive_idx].material_binding;
+        material_binding.set_shadow_bias(&self.wgpu_context.queue, depth_bias, normal_bias);
+        Ok(())
+    }
+
     pub fn get_camera_mut(&mut self) -> &mut Camera {
         &mut self.world.camera
     }
 
-    pub fn update_camera(&self) {
-        self.world_binding.camera_binding.update(&self.world.camera.to_camera_uniform(), &self.wgpu_context.queue);
+    pub fn get_lights_mut(&mut self) -> &mut Lights {
+        &mut self.world.lights
+    }
+
+    // uploads the punctual lights and the global shadow biases after changes through get_lights_mut
+    pub fn update_lights(&mut self) {
+        self.world_binding.lights_binding.update(
+            &self.world.lights, &self.wgpu_context.device, &self.wgpu_context.queue,
+            &self.lights_bind_group_layout, &self.shadow_map
+        );
+    }
+
+    pub fn update_camera(&mut self) {
+        if let Some(bounds) = &self.world.bounds {
+            self.world.camera.fit_clip_planes(bounds);
+        }
+        let camera_uniform = match self.world.camera.checked_camera_uniform() {
+            Some(camera_uniform) => camera_uniform,
+            None => return,
+        };
+        self.cull(&Frustum::from_view_proj(&camera_uniform.view_proj.into()));
+        self.world_binding.camera_binding.update(&camera_uniform, &self.wgpu_context.queue);
+        let sun_view_proj = self.world.lights.sun_view_proj(&camera_uniform.view_proj.into(), self.world.bounds.as_ref());
+        self.world_binding.lights_binding.update_light_view_proj(&sun_view_proj, &self.wgpu_context.queue);
+        self.update_post_settings();
+    }
+
+    fn cull(&mut self, frustum: &Frustum) {
+        let queue = &self.wgpu_context.queue;
+        let stats = self.world_binding.pbr_mesh_bindings.iter_mut()
+            .map(|mesh| mesh.cull(queue, frustum))
+            .fold(CullStats::default(), |a, b| CullStats { drawn: a.drawn + b.drawn, culled: a.culled + b.culled });
+        if stats != self.cull_stats {
+            log::debug!("frustum culling: {} instances drawn, {} culled", stats.drawn, stats.culled);
+            self.cull_stats = stats;
+        }
+    }
+
+    // instances drawn vs culled as of the last camera update
+    pub fn cull_stats(&self) -> CullStats {
+        self.cull_stats
     }
 }
 
+fn index_node_names(pbr_meshes: &[Mesh]) -> HashMap<String, (usize, usize)> {
+    let mut node_names = HashMap::new();
+    for mesh in pbr_meshes {
+        for (instance_idx, name) in mesh.instance_names.iter().enumerate() {
+            if let Some(name) = name {
+                match node_names.entry(name.clone()) {
+                    Entry::Occupied(_) => log::debug!("duplicate node name {}, find_instance returns the first one", name),
+                    Entry::Vacant(entry) => { entry.insert((mesh.gltf_mesh_idx, instance_idx)); },
+                }
+            }
+        }
+    }
+    node_names
+}
@@ -0,0 +1,45 @@
+use std::{fs, path::Path};
+
+/*
+* Resolves `#include "file.wgsl"` lines, so definitions shared between shaders (FrameGlobals) live in one file.
+* The included file is looked up next to the including one and pasted in place of the line.
+* Includes don't nest. Also used by build.rs, which has to see the same source as the renderer.
+*/
+pub fn resolve_includes(path: &str, source: &str) -> Result<String, String> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut out = String::with_capacity(source.len());
+    for line in source.lines() {
+        match line.trim().strip_prefix("#include ") {
+            Some(include) => {
+                let include_path = dir.join(include.trim().trim_matches('"'));
+                let included = fs::read_to_string(&include_path)
+                    .map_err(|e| format!("{}: #include {}: {}", path, include_path.display(), e))?;
+                out.push_str(&included);
+            },
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shaders_get_one_frame_globals() {
+        for path in ["src/renderer/shaders/pbr.wgsl", "src/renderer/shaders/skybox.wgsl", "src/renderer/shaders/post_processing.wgsl"] {
+            let source = fs::read_to_string(path).unwrap();
+            let resolved = resolve_includes(path, &source).unwrap();
+            assert_eq!(resolved.matches("struct FrameGlobals").count(), 1, "{}", path);
+            assert!(!resolved.contains("#include"), "{}", path);
+        }
+    }
+
+    #[test]
+    fn missing_include_is_an_error() {
+        let error = resolve_includes("src/renderer/shaders/pbr.wgsl", "#include \"missing.wgsl\"\n").unwrap_err();
+        assert!(error.contains("missing.wgsl"), "{}", error);
+    }
+}
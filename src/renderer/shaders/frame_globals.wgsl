@@ -0,0 +1,9 @@
+// FrameGlobals in frame_globals.rs, included by the shaders that bind the camera group
+struct FrameGlobals {
+    time: f32,
+    delta_time: f32,
+    // the logger's frame counter
+    frame_index: u32,
+    viewport_size: vec2<f32>,
+    spare: vec2<f32>,
+}
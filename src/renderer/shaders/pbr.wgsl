@@ -1,8 +1,50 @@
+#include "frame_globals.wgsl"
+
+// SunUniform in lights.rs
+struct Sun {
+    direction: vec3<f32>,
+    // shadow map depth units
+    shadow_depth_bias: f32,
+    // color * illuminance
+    color: vec3<f32>,
+    // normal offset in shadow map texels
+    shadow_normal_bias: f32,
+}
+
+// MaterialParams in pbr.rs
+struct MaterialParams {
+    // override of the sun's shadow biases, negative components use the global value
+    shadow_bias: vec2<f32>,
+    normal_texture_scale: f32,
+}
+
+// PunctualLightUniform in lights.rs
+struct PunctualLight {
+    position: vec3<f32>,
+    // 0 for no range
+    range: f32,
+    direction: vec3<f32>,
+    spot_scale: f32,
+    // color * intensity
+    color: vec3<f32>,
+    spot_offset: f32,
+}
+
+// PunctualLightsHeader in lights.rs, lights.rs rewrites this to a fixed size uniform array without storage buffers
+struct PunctualLights {
+    count: u32,
+    lights: array<PunctualLight>,
+}
+
 @group(0) @binding(0) var<uniform> view_proj: mat4x4<f32>;
 @group(0) @binding(1) var<uniform> camera_position: vec3<f32>;
+@group(0) @binding(3) var<uniform> frame_globals: FrameGlobals;
 
-@group(1) @binding(0) var<uniform> light_dir: vec3<f32>;
-@group(1) @binding(1) var<uniform> light_col: vec3<f32>;
+@group(1) @binding(0) var<uniform> sun: Sun;
+@group(1) @binding(1) var<uniform> light_view_proj: mat4x4<f32>;
+@group(1) @binding(2) var shadow_map: texture_depth_2d;
+@group(1) @binding(3) var shadow_sampler: sampler_comparison;
+@group(1) @binding(4) var<storage, read> punctual_lights: PunctualLights;
 
 @group(2) @binding(0) var<uniform> base_color_factor: vec4<f32>;
 @group(2) @binding(1) var<uniform> metallic_factor: f32;
@@ -18,7 +60,7 @@
 @group(2) @binding(11) var base_color_texture_sampler: sampler;
 @group(2) @binding(12) var metallic_roughness_texture: texture_2d<f32>;
 @group(2) @binding(13) var metallic_roughness_texture_sampler: sampler;
-@group(2) @binding(14) var<uniform> normal_texture_scale: f32;
+@group(2) @binding(14) var<uniform> material_params: MaterialParams;
 
 @group(3) @binding(0) var environment_texture: texture_cube<f32>;
 @group(3) @binding(1) var environment_texture_sampler: sampler;
@@ -66,7 +108,6 @@ struct VertexOutput {
 }
 
 const PI: f32 = 3.1415927;
-const MAX_REFLECTION_LOD: f32 = 4.0;
 
 @vertex
 fn vs_main(
@@ -147,6 +188,62 @@ fn fresnel_schlick_roughness(cos_theta: f32, F0: vec3f, roughness: f32) -> vec3f
     return F0 + (max(vec3f(1.0 - roughness), F0) - F0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
 }
 
+// reflected radiance from one light, cook-torrance specular and lambert diffuse
+fn direct_light(N: vec3f, V: vec3f, L: vec3f, radiance: vec3f, F0: vec3f, albedo: vec3f, metallic: f32, roughness: f32) -> vec3f {
+    let H = normalize(V + L);
+    let NDF = distribution_ggx(N, H, roughness);
+    let G = geometry_smith(N, V, L, roughness);
+    let F = fresnel_schlick(max(dot(H, V), 0.0), F0);
+
+    let omega_0 = max(dot(N, V), 0.0);
+    let omega_i = max(dot(N, L), 0.0);
+    let num = NDF * G * F;
+    let denom = 4.0 * max(omega_0 * omega_i, 0.0001);
+    let specular = num / denom;
+
+    let k_d = (vec3f(1.0) - F) * (1.0 - metallic); // diffuse/refracted
+
+    let NdotL = max(dot(N, L), 0.0);
+    return (k_d * albedo / PI + specular) * radiance * NdotL;
+}
+
+// inverse square falloff, windowed to reach 0 at the range (KHR_lights_punctual recommendation)
+fn distance_attenuation(distance: f32, range: f32) -> f32 {
+    let inverse_square = 1.0 / max(distance * distance, 0.0001);
+    if (range <= 0.0) {
+        return inverse_square;
+    }
+    let window = clamp(1.0 - pow(distance / range, 4.0), 0.0, 1.0);
+    return window * window * inverse_square;
+}
+
+// fraction of the sun that reaches the point, 3x3 pcf
+fn sun_visibility(world_position: vec3f, geometric_normal: vec3f) -> f32 {
+    let global_bias = vec2f(sun.shadow_depth_bias, sun.shadow_normal_bias);
+    let bias = select(material_params.shadow_bias, global_bias, material_params.shadow_bias < vec2f(0.0));
+    // world size of a shadow map texel, the projection is orthographic
+    let light_x = vec3f(light_view_proj[0].x, light_view_proj[1].x, light_view_proj[2].x);
+    let texel_world_size = 2.0 / (f32(textureDimensions(shadow_map).x) * length(light_x));
+    // moving the lookup off the surface keeps it from shadowing itself at grazing angles (acne)
+    let offset_position = world_position + geometric_normal * bias.y * texel_world_size;
+    let light_clip = light_view_proj * vec4f(offset_position, 1.0);
+    let ndc = light_clip.xyz / light_clip.w;
+    // outside of the shadow map is lit
+    if (any(abs(ndc.xy) > vec2f(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // ndc y points up, texture v down
+    let uv = vec2f(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    let texel = 1.0 / vec2f(textureDimensions(shadow_map));
+    var visibility = 0.0;
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            visibility += textureSampleCompareLevel(shadow_map, shadow_sampler, uv + vec2f(f32(x), f32(y)) * texel, ndc.z - bias.x);
+        }
+    }
+    return visibility / 9.0;
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let normal_sample = 
@@ -157,7 +254,7 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         );
     var N = in.normal;
     if (normal_sample.w > 0.5) { // w encodes whether normal mapping should be used
-        let n: vec3f = normalize(normal_sample.rgb * 2.0 - 1.0) * vec3f(normal_texture_scale, normal_texture_scale, 1.0);
+        let n: vec3f = normalize(normal_sample.rgb * 2.0 - 1.0) * vec3f(material_params.normal_texture_scale, material_params.normal_texture_scale, 1.0);
         let TBN = mat3x3(in.tangent, in.bitangent, in.normal);
         N = normalize(TBN * n);
     }
@@ -190,31 +287,29 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         );
     let surface_emissive = surface_emissive_sample.rgb * emissive_factor;
     let ao = textureSample(occlusion_texture, occlusion_texture_sampler, in.occlusion_tex_coords);
-    let prefiltered_color = textureSampleLevel(environment_texture, environment_texture_sampler, R, surface_roughness * MAX_REFLECTION_LOD).rgb;
+    // the prefiltered mips go from roughness 0 to 1, the last one is never smaller than 4x4
+    let max_reflection_lod = f32(textureNumLevels(environment_texture) - 1u);
+    let prefiltered_color = textureSampleLevel(environment_texture, environment_texture_sampler, R, surface_roughness * max_reflection_lod).rgb;
 
     let F0 = mix(vec3f(0.04), surface_color.xyz, surface_metallic);
 
     // ---------------- //
     // For each light
     // ---------------- //
-    let L = normalize(-light_dir); // reverse light direction (pointing from surface toward light source)
-    let H = normalize(V + L);
-    let radiance = light_col; // * attenuation (but we assume no attenuation for sunlight)
-
-    let NDF = distribution_ggx(N, H, surface_roughness);
-    let G = geometry_smith(N, V, L, surface_roughness);
-    let F = fresnel_schlick(max(dot(H, V), 0.0), F0);
-
-    let omega_0 = max(dot(N, V), 0.0);
-    let omega_i = max(dot(N, L), 0.0);
-    let num = NDF * G * F;
-    let denom = 4.0 * max(omega_0 * omega_i, 0.0001);
-    let specular = num / denom;
-
-    let k_d = (vec3f(1.0) - F) * (1.0 - surface_metallic); // diffuse/refracted
-
-    let NdotL = max(dot(N, L), 0.0);
-    let Lo = (k_d * surface_color.xyz / PI + specular) * radiance * NdotL;
+    let L = normalize(-sun.direction); // reverse light direction (pointing from surface toward light source)
+    let radiance = sun.color * sun_visibility(in.world_position.xyz, normalize(in.normal)); // * attenuation (but we assume no attenuation for sunlight)
+    var Lo = direct_light(N, V, L, radiance, F0, surface_color.xyz, surface_metallic, surface_roughness);
+
+    // point and spot lights, unshadowed
+    for (var i = 0u; i < punctual_lights.count; i++) {
+        let light = punctual_lights.lights[i];
+        let to_light = light.position - in.world_position.xyz;
+        let distance = length(to_light);
+        let L_p = to_light / max(distance, 0.0001);
+        let cone = clamp(dot(light.direction, -L_p) * light.spot_scale + light.spot_offset, 0.0, 1.0);
+        let radiance_p = light.color * distance_attenuation(distance, light.range) * cone * cone;
+        Lo += direct_light(N, V, L_p, radiance_p, F0, surface_color.xyz, surface_metallic, surface_roughness);
+    }
 
     // ---------------- //
     // IBL
@@ -1,7 +1,41 @@
+#include "frame_globals.wgsl"
+
+struct LutParams {
+    domain_min: vec3<f32>,
+    size: f32,
+    domain_max: vec3<f32>,
+    // 1 if the lut expects srgb encoded input and returns srgb encoded output
+    srgb: u32,
+}
+
+struct DebugParams {
+    // 0 final, 1 resolve, 2 skybox, 3 coverage, 4 depth, 5 shadow map
+    view: u32,
+    // view distance shown as white in the depth view
+    depth_range: f32,
+    znear: f32,
+    zfar: f32,
+}
+
 @group(0) @binding(0) var skybox_texture: texture_2d<f32>;
 @group(0) @binding(1) var skybox_texture_sampler: sampler;
 @group(0) @binding(2) var resolve_texture: texture_2d<f32>;
 @group(0) @binding(3) var resolve_texture_sampler: sampler;
+@group(0) @binding(4) var<uniform> exposure: f32;
+// 0 none, 1 reinhard, 2 aces
+@group(0) @binding(5) var<uniform> tonemapper: u32;
+
+// 0 ungraded, 1 fully graded
+@group(0) @binding(6) var<uniform> lut_weight: f32;
+@group(0) @binding(7) var lut_texture: texture_3d<f32>;
+@group(0) @binding(8) var lut_sampler: sampler;
+@group(0) @binding(9) var<uniform> lut_params: LutParams;
+
+@group(0) @binding(10) var<uniform> debug_params: DebugParams;
+@group(0) @binding(11) var depth_texture: texture_depth_multisampled_2d;
+@group(0) @binding(12) var shadow_map: texture_depth_2d;
+
+@group(1) @binding(3) var<uniform> frame_globals: FrameGlobals;
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
@@ -31,6 +65,61 @@ fn vs_main(
     return out;
 }
 
+fn linear_to_srgb(c: vec3f) -> vec3f {
+    return select(1.055 * pow(c, vec3f(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3f(0.0031308));
+}
+
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    return select(pow((c + 0.055) / 1.055, vec3f(2.4)), c / 12.92, c <= vec3f(0.04045));
+}
+
+fn grade(col: vec3f) -> vec3f {
+    var c = clamp(col, vec3f(0.0), vec3f(1.0));
+    if lut_params.srgb == 1u {
+        c = linear_to_srgb(c);
+    }
+    let n = clamp((c - lut_params.domain_min) / (lut_params.domain_max - lut_params.domain_min), vec3f(0.0), vec3f(1.0));
+    // texel centers, so 0 and 1 hit the first and last entries exactly
+    let coords = (n * (lut_params.size - 1.0) + 0.5) / lut_params.size;
+    var graded = textureSampleLevel(lut_texture, lut_sampler, coords, 0.0).rgb;
+    if lut_params.srgb == 1u {
+        graded = srgb_to_linear(graded);
+    }
+    return graded;
+}
+
+// inverse of the 0..1 perspective depth mapping
+fn linear_depth(depth: f32) -> f32 {
+    return debug_params.znear * debug_params.zfar / (debug_params.zfar - depth * (debug_params.zfar - debug_params.znear));
+}
+
+fn debug_view(in: VertexOutput, uv: vec2f, skybox_col: vec3f, resolve_sample: vec4f) -> vec3f {
+    switch debug_params.view {
+        case 1u: {
+            return resolve_sample.xyz * exposure;
+        }
+        case 2u: {
+            return skybox_col * exposure;
+        }
+        case 3u: {
+            return vec3f(resolve_sample.w);
+        }
+        case 4u: {
+            let depth = textureLoad(depth_texture, vec2i(in.clip_position.xy), 0);
+            // nothing was drawn there
+            if depth >= 1.0 {
+                return vec3f(1.0, 0.0, 1.0);
+            }
+            return vec3f(clamp(linear_depth(depth) / debug_params.depth_range, 0.0, 1.0));
+        }
+        default: {
+            let dims = vec2f(textureDimensions(shadow_map));
+            let texel = min(vec2i(uv * dims), vec2i(dims) - 1);
+            return vec3f(textureLoad(shadow_map, texel, 0));
+        }
+    }
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let uv = vec2f(in.tex_coords.x, 1.0 - in.tex_coords.y);
@@ -48,15 +137,29 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
             uv
         );
 
+    if debug_params.view != 0u {
+        return vec4f(debug_view(in, uv, skybox_sample.xyz, resolve_sample), 1.0);
+    }
+
     var col = mix(skybox_sample.xyz, resolve_sample.xyz, resolve_sample.w);
 
-    // exposure
-    let exposure = 1.0;
-    let exposure_factor = pow(2.0, exposure);
-    col = col * exposure_factor;
+    // exposure, computed from the camera's ev100
+    col = col * exposure;
+
+    switch tonemapper {
+        case 1u: {
+            col = col / (col + vec3f(1.0));
+        }
+        case 2u: {
+            col = clamp((col * (2.51 * col + 0.03)) / (col * (2.43 * col + 0.59) + 0.14), vec3f(0.0), vec3f(1.0));
+        }
+        default: {}
+    }
 
-    // reinhard tone mapping
-    col = col / (col + vec3f(1.0));
+    // color grade after tonemapping, skipped entirely when off so the ungraded image is untouched
+    if lut_weight > 0.0 {
+        col = mix(col, grade(col), lut_weight);
+    }
 
     return vec4f(col, 1.0);
 }
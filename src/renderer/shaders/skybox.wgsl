@@ -1,4 +1,7 @@
+#include "frame_globals.wgsl"
+
 @group(0) @binding(2) var<uniform> inverse_view_proj_rot: mat4x4<f32>;
+@group(0) @binding(3) var<uniform> frame_globals: FrameGlobals;
 
 @group(1) @binding(0) var environment_texture: texture_cube<f32>;
 @group(1) @binding(1) var environment_texture_sampler: sampler;
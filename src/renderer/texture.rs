@@ -1,3 +1,4 @@
+use image::imageops::FilterType;
 use wgpu::FilterMode;
 
 use super::pipelines::pbr;
@@ -14,6 +15,7 @@ impl Texture {
         queue: &wgpu::Queue,
         img_and_sampler: &(image::DynamicImage, Option<pbr::SamplerOptions>),
         srgb: bool,
+        generate_mips: bool,
     ) -> Self {
         let img = &img_and_sampler.0;
         let sampler_options = &img_and_sampler.1;
@@ -24,32 +26,17 @@ impl Texture {
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
-        let (remapped, format): (Vec<u8>, wgpu::TextureFormat) = match (img, srgb) {
-            (image::DynamicImage::ImageRgb32F(_), false) => (
-                bytemuck::cast_slice(&img.to_rgba32f().into_raw()).to_vec(),
-                wgpu::TextureFormat::Rgba32Float,
-            ),
-            (image::DynamicImage::ImageRgba32F(_), false) => (
-                bytemuck::cast_slice(&img.to_rgba32f().into_raw()).to_vec(),
-                wgpu::TextureFormat::Rgba32Float,
-            ),
-            (_, true) => (
-                bytemuck::cast_slice(&img.to_rgba8().into_raw()).to_vec(),
-                wgpu::TextureFormat::Rgba8UnormSrgb,
-            ),
-            (_, false) => (
-                bytemuck::cast_slice(&img.to_rgba8().into_raw()).to_vec(),
-                wgpu::TextureFormat::Rgba8Unorm,
-            ),
-        };
-        let bytes_per_row = match format {
-            wgpu::TextureFormat::Rgba32Float => 4 * 4 * dimensions.0,
-            _ => 4 * dimensions.0
+        let format = match (img, srgb) {
+            (image::DynamicImage::ImageRgb32F(_), false) => wgpu::TextureFormat::Rgba32Float,
+            (image::DynamicImage::ImageRgba32F(_), false) => wgpu::TextureFormat::Rgba32Float,
+            (_, true) => wgpu::TextureFormat::Rgba8UnormSrgb,
+            (_, false) => wgpu::TextureFormat::Rgba8Unorm,
         };
+        let mip_level_count = if generate_mips { full_mip_level_count(dimensions) } else { 1 };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
@@ -57,26 +44,39 @@ impl Texture {
             view_formats: &[],
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &remapped,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(bytes_per_row),
-                rows_per_image: Some(dimensions.1),
-            },
-            size
-        );
+        let mut level_img = None;
+        for mip_level in 0..mip_level_count {
+            let level_size = size.mip_level_size(mip_level, wgpu::TextureDimension::D2);
+            // each level is filtered down from the previous one
+            let current = match level_img.take() {
+                None => img.clone(),
+                Some(previous) => downsample(&previous, level_size.width, level_size.height, srgb),
+            };
+            let (remapped, bytes_per_row) = image_to_bytes(&current, format);
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &remapped,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(level_size.height),
+                },
+                level_size
+            );
+            level_img = Some(current);
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor {
             format: Some(format),
             ..Default::default()
         });
+        // 32 bit float textures aren't filterable
+        let filterable = format != wgpu::TextureFormat::Rgba32Float;
         let sampler = device.create_sampler(
             &sampler_options.as_ref().map(
                 |s| wgpu::SamplerDescriptor {
@@ -84,13 +84,106 @@ impl Texture {
                     address_mode_v: s.address_mode_v,
                     mag_filter: s.mag_filter,
                     min_filter: s.min_filter,
-                    mipmap_filter: if format == wgpu::TextureFormat::Rgba32Float { FilterMode::Nearest } else { FilterMode::Linear },
+                    mipmap_filter: match s.mipmap_filter {
+                        Some(FilterMode::Linear) if filterable => FilterMode::Linear,
+                        _ => FilterMode::Nearest,
+                    },
+                    // no mipmapping, stay on the base level
+                    lod_max_clamp: if s.mipmap_filter.is_some() { 32.0 } else { 0.0 },
                     ..wgpu::SamplerDescriptor::default()
                 }
-            ).unwrap_or(wgpu::SamplerDescriptor::default())
+            ).unwrap_or(wgpu::SamplerDescriptor {
+                mipmap_filter: if filterable { FilterMode::Linear } else { FilterMode::Nearest },
+                ..wgpu::SamplerDescriptor::default()
+            })
         );
 
         Self { view, sampler, texture }
     }
+
+    /*
+    * Overwrites part of the texture in place, for procedural or cpu rendered content that changes every frame.
+    * The texture object stays the same, so bind groups referencing it don't need to be rebuilt.
+    * The write is staged by the queue and lands before the next submitted pass.
+    * The lower mips are updated with the region filtered down, this is exact when the origin and
+    * size are multiples of 2^level, otherwise the edges of the region are slightly off in those mips.
+    * A region that doesn't fit inside the texture is an error and nothing is written.
+    */
+    pub fn write_region(&self, queue: &wgpu::Queue, origin: (u32, u32), img: &image::DynamicImage) -> Result<(), String> {
+        let (width, height) = image::GenericImageView::dimensions(img);
+        let fits = |start: u32, size: u32, limit: u32| start.checked_add(size).is_some_and(|end| end <= limit);
+        if !fits(origin.0, width, self.texture.width()) || !fits(origin.1, height, self.texture.height()) {
+            return Err(format!(
+                "Texture region {:?} + {}x{} is outside the {}x{} texture",
+                origin, width, height, self.texture.width(), self.texture.height()
+            ));
+        }
+        let srgb = self.texture.format().is_srgb();
+        let mut level_img = img.clone();
+        for mip_level in 0..self.texture.mip_level_count() {
+            let level_origin = (origin.0 >> mip_level, origin.1 >> mip_level);
+            let level_size = self.texture.size().mip_level_size(mip_level, wgpu::TextureDimension::D2);
+            // the region can't extend past the (rounded down) level size
+            let region_width = (width >> mip_level).max(1).min(level_size.width - level_origin.0);
+            let region_height = (height >> mip_level).max(1).min(level_size.height - level_origin.1);
+            if mip_level > 0 {
+                level_img = downsample(&level_img, region_width, region_height, srgb);
+            }
+            let (bytes, bytes_per_row) = image_to_bytes(&level_img, self.texture.format());
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &self.texture,
+                    mip_level,
+                    origin: wgpu::Origin3d { x: level_origin.0, y: level_origin.1, z: 0 },
+                },
+                &bytes,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(region_height),
+                },
+                wgpu::Extent3d { width: region_width, height: region_height, depth_or_array_layers: 1 },
+            );
+        }
+        Ok(())
+    }
+}
+
+// down to 1x1
+fn full_mip_level_count(dimensions: (u32, u32)) -> u32 {
+    u32::BITS - dimensions.0.max(dimensions.1).max(1).leading_zeros()
+}
+
+// srgb images are filtered in linear space, so the mips don't darken
+fn downsample(img: &image::DynamicImage, width: u32, height: u32, srgb: bool) -> image::DynamicImage {
+    if !srgb {
+        return img.resize_exact(width, height, FilterType::Triangle);
+    }
+    let mut linear = img.to_rgba32f();
+    for px in linear.pixels_mut() {
+        for c in &mut px.0[..3] {
+            *c = c.powf(2.2);
+        }
+    }
+    let mut resized = image::imageops::resize(&linear, width, height, FilterType::Triangle);
+    for px in resized.pixels_mut() {
+        for c in &mut px.0[..3] {
+            *c = c.powf(1.0 / 2.2);
+        }
+    }
+    image::DynamicImage::ImageRgba32F(resized)
+}
+
+// returns the pixels converted to the texture format, and the bytes per row
+fn image_to_bytes(img: &image::DynamicImage, format: wgpu::TextureFormat) -> (Vec<u8>, u32) {
+    let width = image::GenericImageView::width(img);
+    match format {
+        wgpu::TextureFormat::Rgba32Float => (
+            bytemuck::cast_slice(&img.to_rgba32f().into_raw()).to_vec(),
+            4 * 4 * width,
+        ),
+        _ => (img.to_rgba8().into_raw(), 4 * width),
+    }
 }
 
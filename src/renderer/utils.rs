@@ -1,8 +1,7 @@
-fn read_shaders(path: &str) -> std::io::Result<String> {
-    let mut file = std::fs::File::open(path)?;
-    let mut contents = String::new();
-    std::io::Read::read_to_string(&mut file, &mut contents)?;
-    Ok(contents)
+// the shader source with its includes resolved, see shader_source
+fn read_shaders(path: &str) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    super::shader_source::resolve_includes(path, &contents)
 }
 
 fn read_fallback_shaders() -> std::io::Result<String> {
@@ -12,26 +11,60 @@ fn read_fallback_shaders() -> std::io::Result<String> {
     Ok(contents)
 }
 
-pub fn create_shader_module(device: &wgpu::Device, path: &str) -> wgpu::ShaderModule {
+// a numeric setting from the environment, None (with a warning) if it doesn't parse
+pub fn env_f32(name: &str) -> Option<f32> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log::warn!("{}: {} isn't a number, using the default", name, value);
+            None
+        },
+    }
+}
+
+/*
+* Runs f inside a validation error scope. Without a scope validation errors go to the
+* uncaptured error handler, which panics, with one the caller can discard the invalid object.
+*/
+pub fn validated<T>(device: &wgpu::Device, f: impl FnOnce() -> T) -> Result<T, wgpu::Error> {
     device.push_error_scope(wgpu::ErrorFilter::Validation);
-    {
-        let source = wgpu::ShaderSource::Wgsl(read_shaders(path).unwrap_or_else(|e| {
-            println!("Error reading shader: {}", e);
+    let result = f();
+    device.poll(wgpu::Maintain::Wait);
+    match pollster::FutureExt::block_on(device.pop_error_scope()) {
+        Some(e) => Err(e),
+        None => Ok(result),
+    }
+}
+
+// compiles the shader only to get its diagnostics, there is no fallback
+pub fn check_shader(device: &wgpu::Device, path: &str) -> Result<(), String> {
+    let source = read_shaders(path)?;
+    validated(device, || {
+        device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(path),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        })
+    }).map(|_| ()).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn create_shader_module(device: &wgpu::Device, path: &str) -> wgpu::ShaderModule {
+    create_shader_module_with(device, path, |source| source)
+}
+
+// like create_shader_module, patch rewrites the source from disk (not the fallback) before compiling
+pub fn create_shader_module_with(device: &wgpu::Device, path: &str, patch: impl FnOnce(String) -> String) -> wgpu::ShaderModule {
+    validated(device, || {
+        let source = wgpu::ShaderSource::Wgsl(read_shaders(path).map(patch).unwrap_or_else(|e| {
+            log::error!("Error reading shader: {}", e);
             read_fallback_shaders().unwrap()
         }).into());
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
             source
-        });
-
-        device.poll(wgpu::Maintain::Wait);
-        let error = pollster::FutureExt::block_on(device.pop_error_scope());
-        match error {
-            Some(e) => Err(e),
-            None => Ok(shader),
-        }
-    }.unwrap_or_else(|e| {
-        println!("Shader compilation failed: {}", e);
+        })
+    }).unwrap_or_else(|e| {
+        log::error!("Shader compilation failed: {}", e);
         let source = wgpu::ShaderSource::Wgsl(read_fallback_shaders().unwrap().into());
         device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
@@ -0,0 +1,203 @@
+// UV sanity checks run at import time:
+// - uv bounds per texcoord set
+// - overlapping triangles (matters for the occlusion/lightmap set, which must be unique)
+// - texel density in texels per world unit (object space)
+
+use std::sync::OnceLock;
+
+use super::utils::env_f32;
+
+// uvs outside [-CLAMP_TOLERANCE, 1 + CLAMP_TOLERANCE] smear when the sampler clamps
+pub const CLAMP_TOLERANCE: f32 = 0.01;
+const DEFAULT_MAX_OVERLAP_RATIO: f32 = 0.01;
+const OCCUPANCY_GRID_SIZE: usize = 256;
+
+/*
+* What the checks warn about, read from the environment once:
+* WGPU_TEST_UV_OVERLAP_RATIO, the fraction of covered occupancy grid cells that may be covered more than once
+* WGPU_TEST_TEXEL_DENSITY_MIN / WGPU_TEST_TEXEL_DENSITY_MAX, texels per unit, unset bounds don't warn
+*/
+pub struct UvLimits {
+    pub max_overlap_ratio: f32,
+    pub min_texel_density: f32,
+    pub max_texel_density: f32,
+}
+
+pub fn limits() -> &'static UvLimits {
+    static LIMITS: OnceLock<UvLimits> = OnceLock::new();
+    LIMITS.get_or_init(|| UvLimits {
+        max_overlap_ratio: env_f32("WGPU_TEST_UV_OVERLAP_RATIO").unwrap_or(DEFAULT_MAX_OVERLAP_RATIO),
+        min_texel_density: env_f32("WGPU_TEST_TEXEL_DENSITY_MIN").unwrap_or(0.0),
+        max_texel_density: env_f32("WGPU_TEST_TEXEL_DENSITY_MAX").unwrap_or(f32::INFINITY),
+    })
+}
+
+pub struct UvBounds {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl UvBounds {
+    pub fn from_uvs(uvs: &[[f32; 2]]) -> Self {
+        let mut min = [f32::MAX, f32::MAX];
+        let mut max = [f32::MIN, f32::MIN];
+        for uv in uvs {
+            min = [min[0].min(uv[0]), min[1].min(uv[1])];
+            max = [max[0].max(uv[0]), max[1].max(uv[1])];
+        }
+        Self { min, max }
+    }
+
+    pub fn exceeds_unit_square(&self, axis: usize) -> bool {
+        self.min[axis] < -CLAMP_TOLERANCE || self.max[axis] > 1.0 + CLAMP_TOLERANCE
+    }
+}
+
+pub struct TexelDensity {
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+}
+
+// triangles with every index below vertex_count, malformed ones are skipped (see invalid_triangles)
+fn triangles(indices: &[u32], vertex_count: usize) -> impl Iterator<Item = [usize; 3]> + '_ {
+    indices.chunks_exact(3)
+        .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+        .filter(move |t| t.iter().all(|i| *i < vertex_count))
+}
+
+// triangles that index past the end of the vertex attributes, the checks skip them
+pub fn invalid_triangles(indices: &[u32], vertex_count: usize) -> usize {
+    indices.chunks_exact(3).filter(|t| t.iter().any(|i| *i as usize >= vertex_count)).count()
+}
+
+fn edge(a: [f32; 2], b: [f32; 2], p: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+/*
+* Rasterizes every triangle into an occupancy grid over the unit square (texel centers, wrapped)
+* and returns the fraction of covered cells that more than one triangle touched.
+*/
+pub fn overlap_ratio(uvs: &[[f32; 2]], indices: &[u32]) -> f32 {
+    let n = OCCUPANCY_GRID_SIZE;
+    let mut grid = vec![0u16; n * n];
+
+    for [i0, i1, i2] in triangles(indices, uvs.len()) {
+        let scale = |uv: [f32; 2]| [uv[0] * n as f32, uv[1] * n as f32];
+        let (a, b, c) = (scale(uvs[i0]), scale(uvs[i1]), scale(uvs[i2]));
+        let area = edge(a, b, c);
+        if area == 0.0 {
+            continue;
+        }
+
+        let min_x = a[0].min(b[0]).min(c[0]).floor() as i64;
+        // a triangle can't cover more than the whole (wrapped) grid
+        let max_x = (a[0].max(b[0]).max(c[0]).ceil() as i64).min(min_x + n as i64);
+        let min_y = a[1].min(b[1]).min(c[1]).floor() as i64;
+        let max_y = (a[1].max(b[1]).max(c[1]).ceil() as i64).min(min_y + n as i64);
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let p = [x as f32 + 0.5, y as f32 + 0.5];
+                let (w0, w1, w2) = (edge(b, c, p), edge(c, a, p), edge(a, b, p));
+                // strict, so cells centered on an edge shared by two triangles don't count as overlap
+                let inside = if area > 0.0 {
+                    w0 > 0.0 && w1 > 0.0 && w2 > 0.0
+                } else {
+                    w0 < 0.0 && w1 < 0.0 && w2 < 0.0
+                };
+                if inside {
+                    let cell = y.rem_euclid(n as i64) as usize * n + x.rem_euclid(n as i64) as usize;
+                    grid[cell] = grid[cell].saturating_add(1);
+                }
+            }
+        }
+    }
+
+    let covered = grid.iter().filter(|c| **c > 0).count();
+    let overlapping = grid.iter().filter(|c| **c > 1).count();
+    if covered == 0 { 0.0 } else { overlapping as f32 / covered as f32 }
+}
+
+pub fn texel_density(
+    positions: &[[f32; 3]],
+    uvs: &[[f32; 2]],
+    indices: &[u32],
+    texture_size: (u32, u32),
+) -> Option<TexelDensity> {
+    let texels = (texture_size.0 as f64 * texture_size.1 as f64) as f32;
+    let mut min = f32::MAX;
+    let mut max = 0f32;
+    let mut total_world_area = 0f32;
+    let mut total_texel_area = 0f32;
+
+    for [i0, i1, i2] in triangles(indices, positions.len().min(uvs.len())) {
+        let (p0, p1, p2) = (
+            cgmath::Vector3::from(positions[i0]),
+            cgmath::Vector3::from(positions[i1]),
+            cgmath::Vector3::from(positions[i2]),
+        );
+        let world_area = 0.5 * cgmath::InnerSpace::magnitude((p1 - p0).cross(p2 - p0));
+        let uv_area = 0.5 * edge(uvs[i0], uvs[i1], uvs[i2]).abs();
+        if world_area == 0.0 || uv_area == 0.0 {
+            continue;
+        }
+        let density = (uv_area * texels / world_area).sqrt();
+        min = min.min(density);
+        max = max.max(density);
+        total_world_area += world_area;
+        total_texel_area += uv_area * texels;
+    }
+
+    if total_world_area == 0.0 {
+        return None;
+    }
+    Some(TexelDensity { min, avg: (total_texel_area / total_world_area).sqrt(), max })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a unit quad as two triangles, in uv and in world space (z = 0)
+    const QUAD_UVS: [[f32; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+    const QUAD_POSITIONS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+    const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    #[test]
+    fn overlapping_lightmap_uvs_are_flagged() {
+        // the same triangle twice, and a third one covering half of it
+        let uvs = [[0.1, 0.1], [0.9, 0.1], [0.9, 0.9], [0.5, 0.1], [0.9, 0.1], [0.9, 0.5]];
+        let ratio = overlap_ratio(&uvs, &[0, 1, 2, 0, 1, 2, 3, 4, 5]);
+        assert!(ratio > DEFAULT_MAX_OVERLAP_RATIO, "ratio {}", ratio);
+        assert!((ratio - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn adjacent_triangles_dont_overlap() {
+        assert_eq!(overlap_ratio(&QUAD_UVS, &QUAD_INDICES), 0.0);
+    }
+
+    #[test]
+    fn texel_density_of_a_unit_quad() {
+        let density = texel_density(&QUAD_POSITIONS, &QUAD_UVS, &QUAD_INDICES, (256, 256)).unwrap();
+        for d in [density.min, density.avg, density.max] {
+            assert!((d - 256.0).abs() < 1e-3, "density {}", d);
+        }
+    }
+
+    #[test]
+    fn texture_size_doesnt_overflow() {
+        let density = texel_density(&QUAD_POSITIONS, &QUAD_UVS, &QUAD_INDICES, (65536, 65536)).unwrap();
+        assert!((density.avg - 65536.0).abs() < 1.0, "density {}", density.avg);
+    }
+
+    #[test]
+    fn out_of_range_indices_are_skipped() {
+        let indices = [0, 1, 2, 0, 2, 7];
+        assert_eq!(invalid_triangles(&indices, QUAD_UVS.len()), 1);
+        assert_eq!(overlap_ratio(&QUAD_UVS, &indices), 0.0);
+        let density = texel_density(&QUAD_POSITIONS, &QUAD_UVS, &indices, (256, 256)).unwrap();
+        assert!((density.avg - 256.0).abs() < 1e-3);
+    }
+}
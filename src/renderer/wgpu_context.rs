@@ -18,41 +18,113 @@ pub struct WgpuContext<'surface> {
     pub queue: wgpu::Queue,
 }
 
-impl WgpuContext<'_> {
-    pub async fn new(window: Arc<Window>) -> Self {
-        let size = window.inner_size();
-
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            ..Default::default()
-        });
+// adapter types in the order they are tried, unless WGPU_TEST_GPU picks one by name
+fn device_type_rank(device_type: wgpu::DeviceType) -> u32 {
+    match device_type {
+        wgpu::DeviceType::DiscreteGpu => 0,
+        wgpu::DeviceType::IntegratedGpu => 1,
+        wgpu::DeviceType::VirtualGpu => 2,
+        wgpu::DeviceType::Other => 3,
+        wgpu::DeviceType::Cpu => 4,
+    }
+}
 
-        let surface = instance.create_surface(window.clone()).unwrap();
+/*
+* Tries every adapter of the given backends that can present to the window, best first,
+* and returns the first one that hands out a device. Each failure is added to attempts.
+*/
+async fn request_device(
+    window: &Arc<Window>,
+    backends: wgpu::Backends,
+    limits: &wgpu::Limits,
+    preferred_name: Option<&str>,
+    attempts: &mut Vec<String>,
+) -> Option<(wgpu::Surface<'static>, wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    });
+    let surface = match instance.create_surface(window.clone()) {
+        Ok(surface) => surface,
+        Err(e) => {
+            attempts.push(format!("{:?}: can't create a surface: {}", backends, e));
+            return None;
+        },
+    };
 
-        let adapter = instance.request_adapter(
-            &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false
-            }
-        ).await.unwrap();
+    let mut adapters = instance.enumerate_adapters(backends);
+    if adapters.is_empty() {
+        attempts.push(format!("{:?}: no adapters", backends));
+        return None;
+    }
+    adapters.sort_by_key(|adapter| {
+        let info = adapter.get_info();
+        let preferred = preferred_name.is_some_and(|name| info.name.to_lowercase().contains(&name.to_lowercase()));
+        (!preferred, device_type_rank(info.device_type))
+    });
 
-        let (device, queue) = adapter.request_device(
+    for adapter in adapters {
+        let info = adapter.get_info();
+        log::debug!("adapter {} ({:?}, {:?}, driver {} {})", info.name, info.backend, info.device_type, info.driver, info.driver_info);
+        if !adapter.is_surface_supported(&surface) {
+            attempts.push(format!("{} ({:?}): can't present to the window", info.name, info.backend));
+            continue;
+        }
+        let result = adapter.request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
                 required_features: wgpu::Features::empty(),
-                required_limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits())
+                required_limits: limits.clone().using_resolution(adapter.limits())
             },
             None,
-        ).await.unwrap();
+        ).await;
+        match result {
+            Ok((device, queue)) => return Some((surface, adapter, device, queue)),
+            Err(e) => attempts.push(format!("{} ({:?}): {}", info.name, info.backend, e)),
+        }
+    }
+    None
+}
+
+impl WgpuContext<'_> {
+    /*
+    * Adapter selection: WGPU_TEST_BACKENDS limits the backends (comma separated, e.g. "vulkan,gl"),
+    * WGPU_TEST_GPU prefers the adapter whose name contains the given text. If no adapter works,
+    * safe mode retries on the gl backend with webgl2 class limits before giving up with a list of
+    * everything that was tried.
+    */
+    pub async fn new(window: Arc<Window>) -> Result<Self, String> {
+        let size = window.inner_size();
+
+        let backends = match std::env::var("WGPU_TEST_BACKENDS") {
+            Ok(list) => wgpu::util::parse_backends_from_comma_list(&list.to_lowercase()),
+            Err(_) => wgpu::Backends::all(),
+        };
+        let preferred_name = std::env::var("WGPU_TEST_GPU").ok();
+
+        let mut attempts = vec![];
+        let mut selected = if backends.is_empty() {
+            attempts.push("WGPU_TEST_BACKENDS allows no backends".to_string());
+            None
+        } else {
+            request_device(&window, backends, &wgpu::Limits::downlevel_defaults(), preferred_name.as_deref(), &mut attempts).await
+        };
+        if selected.is_none() {
+            log::warn!("no usable adapter, trying safe mode (gl, downlevel limits)\n{}", attempts.join("\n"));
+            selected = request_device(&window, wgpu::Backends::GL, &wgpu::Limits::downlevel_webgl2_defaults(), preferred_name.as_deref(), &mut attempts).await;
+        }
+        let (surface, adapter, device, queue) = selected
+            .ok_or_else(|| format!("no usable graphics adapter, tried:\n{}", attempts.join("\n")))?;
+
+        let info = adapter.get_info();
+        log::info!("using {} ({:?}, {:?})", info.name, info.backend, info.device_type);
 
         // device.push_error_scope(wgpu::ErrorFilter::Validation);
 
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
         let surface_config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -66,12 +138,12 @@ impl WgpuContext<'_> {
         };
         surface.configure(&device, &surface_config);
 
-        Self {
+        Ok(Self {
             window,
             surface,
             device,
             queue,
             surface_config,
-        }
+        })
     }
 }